@@ -0,0 +1,213 @@
+use crate::{
+  expand,
+  infer::{self, Inferred},
+  parse::{
+    check_shadowing, parse_capture_list, with_help, AsyncBlock, Capture, Cc, Closure, NamedTarget,
+    Target,
+  },
+};
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::{
+  parse::{Parse, ParseStream},
+  punctuated::Punctuated,
+  spanned::Spanned,
+  visit_mut::{self, VisitMut},
+  Attribute, Expr, Ident, Item, Meta, Token,
+};
+
+/// Expand every closure and async block marked with `#[cc(...)]` in the item.
+///
+/// `#[cc(a, mut b)] move |x| ..` is the same as `cc!([a, mut b], move |x| ..)`.
+///
+/// With `#[clonesure(infer)]`, unmarked `move` closures and `async move` blocks
+/// clone the variables which are used again after them.
+pub fn expand(attr: TokenStream, mut item: Item) -> TokenStream {
+  let mut visitor = Visitor {
+    error: None,
+    inferred: None,
+    report: false,
+  };
+  match syn::parse2::<Options>(attr) {
+    Ok(options) => {
+      if options.infer {
+        visitor.inferred = Some(infer::infer(&item).into_iter());
+      }
+      visitor.report = options.report;
+    }
+    Err(error) => visitor.push(error),
+  }
+
+  visitor.visit_item_mut(&mut item);
+
+  // keep the item even if there are errors, so its uses are not reported as missing
+  let mut tokens = item.into_token_stream();
+  if let Some(error) = visitor.error {
+    tokens.extend(error.into_compile_error());
+  }
+  tokens
+}
+
+/// `#[clonesure(infer, report)]`
+struct Options {
+  infer: bool,
+  /// Report the inferred captures as warnings.
+  report: bool,
+}
+
+impl Parse for Options {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    let help = "use `#[clonesure(infer)]` to infer captures, add `report` to see them";
+    let mut options = Options {
+      infer: false,
+      report: false,
+    };
+    for ident in Punctuated::<Ident, Token![,]>::parse_terminated(input)
+      .map_err(|err| syn::Error::new(err.span(), with_help("expected `infer` or `report`", help)))?
+    {
+      if ident == "infer" {
+        options.infer = true;
+      } else if ident == "report" {
+        options.report = true;
+      } else {
+        return Err(syn::Error::new(
+          ident.span(),
+          with_help("expected `infer` or `report`", help),
+        ));
+      }
+    }
+    Ok(options)
+  }
+}
+
+struct Visitor {
+  error: Option<syn::Error>,
+  /// The inferred captures of the remaining candidates, if `infer` is enabled.
+  inferred: Option<std::vec::IntoIter<Vec<Inferred>>>,
+  report: bool,
+}
+
+impl Visitor {
+  fn push(&mut self, error: syn::Error) {
+    match &mut self.error {
+      Some(existing) => existing.combine(error),
+      None => self.error = Some(error),
+    }
+  }
+}
+
+impl VisitMut for Visitor {
+  fn visit_expr_mut(&mut self, expr: &mut Expr) {
+    // candidates are counted before their children, like `infer::infer` does
+    let inferred = match &mut self.inferred {
+      Some(inferred) if infer::is_candidate(expr) => inferred.next().unwrap_or_default(),
+      _ => Vec::new(),
+    };
+
+    // expand nested closures first, so the outer one captures their output
+    visit_mut::visit_expr_mut(self, expr);
+
+    let attrs = match expr {
+      Expr::Closure(closure) => &mut closure.attrs,
+      Expr::Async(block) => &mut block.attrs,
+      _ => return,
+    };
+    let result = match attrs.iter().position(|attr| attr.path().is_ident("cc")) {
+      // on errors, keep the unmarked expression so it is not reported again
+      Some(index) => {
+        let marker = attrs.remove(index);
+        marker_captures(&marker).and_then(|captures| expand_target(captures, expr.clone()))
+      }
+      None if !inferred.is_empty() => {
+        let report = self.report.then(|| report(&inferred, expr));
+        let captures = inferred.into_iter().map(Inferred::into_capture).collect();
+        expand_target(captures, expr.clone()).map(|tokens| quote! {{ #report #tokens }})
+      }
+      None => return,
+    };
+
+    match result {
+      Ok(tokens) => *expr = Expr::Verbatim(tokens),
+      Err(error) => self.push(error),
+    }
+  }
+}
+
+/// Warn about the inferred captures of `expr`.
+fn report(inferred: &[Inferred], expr: &Expr) -> TokenStream {
+  let names = inferred
+    .iter()
+    .map(|inferred| format!("`{}`", inferred.ident))
+    .collect::<Vec<_>>()
+    .join(", ");
+  let target = match expr {
+    Expr::Async(_) => "async block",
+    _ => "closure",
+  };
+  expand::warning(
+    expr.span(),
+    &format!("inferred captures, cloned {} into the {}", names, target),
+  )
+}
+
+/// Expand a closure or an async block without the `#[cc(...)]` marker.
+fn expand_target(captures: Vec<Capture>, expr: Expr) -> syn::Result<TokenStream> {
+  let (attrs, target) = match expr {
+    Expr::Closure(closure) => {
+      if closure.lifetimes.is_some() || closure.constness.is_some() || closure.movability.is_some()
+      {
+        let (lifetimes, constness, movability) =
+          (&closure.lifetimes, &closure.constness, &closure.movability);
+        return Err(syn::Error::new_spanned(
+          quote! { #lifetimes #constness #movability },
+          "`#[cc]` closures can't be `const`, `static`, or have `for<...>` lifetimes",
+        ));
+      }
+
+      let inputs = closure.inputs.into_iter().collect::<Vec<_>>();
+      check_shadowing(&captures, &inputs)?;
+      (
+        closure.attrs,
+        Target::Closure(Closure {
+          wrapper: None,
+          asyncness: closure.asyncness,
+          by_ref: None,
+          captures: Vec::new(),
+          inputs,
+          output: closure.output,
+          body: closure.body,
+        }),
+      )
+    }
+    Expr::Async(block) => (
+      block.attrs,
+      Target::Async(AsyncBlock {
+        async_token: block.async_token,
+        block: block.block,
+      }),
+    ),
+    _ => unreachable!("only closures and async blocks are expanded"),
+  };
+
+  let tokens = expand::expand(Cc {
+    bounds: Punctuated::new(),
+    captures,
+    targets: vec![NamedTarget { name: None, target }],
+  })?;
+  Ok(quote! { #(#attrs)* #tokens })
+}
+
+/// `#[cc]` captures nothing, `#[cc(a, mut b)]` captures like `cc!([a, mut b], ..)`.
+fn marker_captures(marker: &Attribute) -> syn::Result<Vec<Capture>> {
+  match &marker.meta {
+    Meta::Path(_) => Ok(Vec::new()),
+    Meta::List(_) => marker.parse_args_with(parse_capture_list),
+    Meta::NameValue(_) => Err(syn::Error::new_spanned(
+      marker,
+      with_help(
+        "expected `#[cc]` or `#[cc(...)]`",
+        "list the captures like `#[cc(a, mut b, weak c)]`",
+      ),
+    )),
+  }
+}
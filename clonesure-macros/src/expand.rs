@@ -0,0 +1,1011 @@
+use crate::parse::{
+  with_help, AsyncBlock, Capture, Cc, Closure, Fallback, Guard, Mode, NamedTarget, Source, Target,
+  Wrapper,
+};
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::{
+  punctuated::Punctuated, spanned::Spanned, Block, Expr, ExprBlock, Ident, Pat, ReturnType, Token,
+  TraitBound, TraitBoundModifier, TypeParamBound,
+};
+
+pub fn expand(cc: Cc) -> syn::Result<TokenStream> {
+  let Cc {
+    bounds,
+    captures,
+    mut targets,
+  } = cc;
+
+  if targets.len() == 1 && targets[0].name.is_none() {
+    return expand_target(&bounds, &captures, targets.remove(0).target);
+  }
+  expand_targets(&bounds, &captures, targets)
+}
+
+fn expand_target(
+  bounds: &Bounds,
+  captures: &[Capture],
+  target: Target,
+) -> syn::Result<TokenStream> {
+  match target {
+    Target::Closure(closure) => expand_closure(bounds, captures, closure),
+    Target::Async(block) => expand_async_block(bounds, captures, block),
+  }
+}
+
+/// Closures which share the captures are returned in a tuple, or a struct if they are named.
+/// Each closure only clones the captures which it uses.
+///
+/// ```ignore
+/// {
+///   let closure0 = { let a = a.clone(); move |v| .. };
+///   let closure1 = { let a = a.clone(); move |e| .. };
+///   {
+///     struct Closures<T0, T1> { pub ok: T0, pub err: T1 }
+///     Closures { ok: closure0, err: closure1 }
+///   }
+/// }
+/// ```
+///
+/// Expressions used by many closures are evaluated once into hidden locals, which the
+/// closures clone, and `@try` captures are resolved in front of all closures,
+/// so `cc!` returns a single `Result` of the tuple or the struct.
+fn expand_targets(
+  bounds: &Bounds,
+  captures: &[Capture],
+  mut targets: Vec<NamedTarget>,
+) -> syn::Result<TokenStream> {
+  let bodies = targets
+    .iter()
+    .map(|target| match &target.target {
+      Target::Closure(closure) => closure.body.to_token_stream(),
+      Target::Async(block) => block.block.to_token_stream(),
+    })
+    .collect::<Vec<_>>();
+
+  let warnings = captures
+    .iter()
+    .filter(|capture| !bodies.iter().any(|body| is_used(capture, body)))
+    .map(warn_unused)
+    .collect::<Vec<_>>();
+
+  let by_ref = targets
+    .iter()
+    .map(|target| matches!(&target.target, Target::Closure(closure) if closure.by_ref.is_some()))
+    .collect::<Vec<_>>();
+
+  let mut setup = Vec::new();
+  let mut used = vec![Vec::new(); targets.len()];
+  for (i, capture) in captures.iter().enumerate() {
+    // `ref` closures take the captures on each call, and reject `@try` and expressions
+    let (ref_users, users): (Vec<_>, Vec<_>) = (0..bodies.len())
+      .filter(|k| is_used(capture, &bodies[*k]))
+      .partition(|k| by_ref[*k]);
+    // an unused `@try` still decides whether `cc!` returns a `Result`
+    if ref_users.is_empty() && users.is_empty() {
+      if let Mode::Try(_) = capture.mode {
+        setup.push(store_untyped(capture, quote! { _ }));
+      }
+      continue;
+    }
+    for k in ref_users {
+      used[k].push(capture.clone());
+    }
+    match (&capture.mode, &capture.source) {
+      // each closure needs its own handle
+      (Mode::Try(None), Source::Path(_)) => {
+        for k in users {
+          let local = Ident::new(&format!("shared{}_{}", i, k), Span::mixed_site());
+          setup.push(store_untyped(capture, local.to_token_stream()));
+          used[k].push(moved_from(capture, local));
+        }
+      }
+      (mode, Source::Expr(_, expr)) if users.len() > 1 || matches!(mode, Mode::Try(None)) => {
+        let local = Ident::new(&format!("shared{}", i), Span::mixed_site());
+        setup.push(match mode {
+          Mode::Try(_) => store_untyped(capture, local.to_token_stream()),
+          _ => Setup::Let(quote_spanned! {capture.at.span=> let #local = #expr; }),
+        });
+        if let [k] = users[..] {
+          used[k].push(moved_from(capture, local));
+        } else {
+          for k in users {
+            used[k].push(cloned_from(capture, &local));
+          }
+        }
+      }
+      _ => {
+        for k in users {
+          used[k].push(capture.clone());
+        }
+      }
+    }
+  }
+
+  // `@try` in the parameters of a closure is resolved in front of all closures too
+  for (k, target) in targets.iter_mut().enumerate() {
+    if let Target::Closure(closure @ Closure { by_ref: None, .. }) = &mut target.target {
+      for (i, capture) in closure.captures.iter_mut().enumerate() {
+        if let Mode::Try(None) = capture.mode {
+          let local = Ident::new(&format!("param{}_{}", k, i), Span::mixed_site());
+          setup.push(store_untyped(capture, local.to_token_stream()));
+          *capture = moved_from(capture, local);
+        }
+      }
+    }
+  }
+
+  let mut names = Vec::new();
+  let mut exprs = Vec::new();
+  for (target, used) in targets.into_iter().zip(&used) {
+    exprs.push(expand_target(bounds, used, target.target)?);
+    names.extend(target.name.map(|(name, _)| name));
+  }
+
+  let result = if names.is_empty() {
+    quote! {{
+      #(#warnings)*
+      (#(#exprs,)*)
+    }}
+  } else {
+    let closures = Ident::new("Closures", Span::mixed_site());
+    let params = (0..names.len())
+      .map(|i| Ident::new(&format!("T{}", i), Span::mixed_site()))
+      .collect::<Vec<_>>();
+    let locals = (0..names.len())
+      .map(|i| Ident::new(&format!("closure{}", i), Span::mixed_site()))
+      .collect::<Vec<_>>();
+    // items are not hygienic, so the struct is declared in a block without the user's code
+    quote! {{
+      #(#warnings)*
+      #(let #locals = #exprs;)*
+      {
+        struct #closures<#(#params),*> {
+          #(pub #names: #params,)*
+        }
+        #closures {
+          #(#names: #locals,)*
+        }
+      }
+    }}
+  };
+  if setup.is_empty() {
+    Ok(result)
+  } else {
+    Ok(with_setup(setup, result))
+  }
+}
+
+/// Store the value of a shared capture in `pat`, the type is annotated by each closure.
+fn store_untyped(capture: &Capture, pat: TokenStream) -> Setup {
+  store(
+    &Capture {
+      ty: None,
+      ..capture.clone()
+    },
+    pat,
+  )
+}
+
+/// The capture which moves the value of `local` into a closure.
+fn moved_from(capture: &Capture, local: Ident) -> Capture {
+  Capture {
+    mode: Mode::Clone,
+    deref: None,
+    source: Source::Expr(
+      Token![=](local.span()),
+      Expr::Verbatim(local.into_token_stream()),
+    ),
+    ..capture.clone()
+  }
+}
+
+/// The capture which takes the value of `local` into a closure like it takes a variable.
+fn cloned_from(capture: &Capture, local: &Ident) -> Capture {
+  let mode = match capture.mode {
+    Mode::Try(_) => Mode::Clone,
+    ref mode => mode.clone(),
+  };
+  Capture {
+    mode,
+    deref: None,
+    source: Source::Path(std::iter::once(local.clone()).collect()),
+    ..capture.clone()
+  }
+}
+
+fn expand_closure(
+  bounds: &Bounds,
+  captures: &[Capture],
+  closure: Closure,
+) -> syn::Result<TokenStream> {
+  if closure.by_ref.is_some() {
+    return expand_ref_closure(bounds, captures, closure);
+  }
+
+  let Closure {
+    wrapper,
+    asyncness,
+    captures: param_captures,
+    inputs,
+    output,
+    body,
+    ..
+  } = closure;
+
+  let (setup, entry) = expand_captures(
+    captures.iter().chain(&param_captures),
+    &body.to_token_stream(),
+  );
+  let body = with_entry(&entry, *body);
+
+  let closure = finish_closure(
+    bounds,
+    &wrapper,
+    &inputs,
+    &output,
+    quote! {
+      #asyncness move |#(#inputs),*| #output #body
+    },
+  )?;
+  Ok(with_setup(setup, closure))
+}
+
+/// A `ref` closure borrows its environment, so the captures are taken from the borrowed
+/// variables every time the closure is called, which keeps the closure `Fn` or `FnMut`,
+/// and `@lock` and friends lock or borrow the original variable on entry.
+///
+/// ```ignore
+/// |x| {
+///   let a = a.clone(); // @a or @each a
+///   let b = &b; // @ref b
+///   ..
+/// }
+/// ```
+fn expand_ref_closure(
+  bounds: &Bounds,
+  captures: &[Capture],
+  closure: Closure,
+) -> syn::Result<TokenStream> {
+  let Closure {
+    wrapper,
+    asyncness,
+    captures: param_captures,
+    inputs,
+    output,
+    body,
+    ..
+  } = closure;
+
+  let mut setup = Vec::new();
+  let mut entry = Vec::new();
+
+  let body_tokens = body.to_token_stream();
+  for capture in captures.iter().chain(&param_captures) {
+    let unused = !is_used(capture, &body_tokens);
+    if unused {
+      setup.push(Setup::Let(warn_unused(capture)));
+    }
+
+    let Capture {
+      at,
+      mode,
+      mutability,
+      ident,
+      source,
+      ..
+    } = capture;
+    let ty = typed(capture);
+
+    match (mode, source) {
+      (Mode::Clone, Source::Path(_))
+      | (Mode::Custom(_), Source::Path(_))
+      | (Mode::With(_), Source::Path(_))
+      | (Mode::Owned, Source::Path(_))
+      | (Mode::Into(_), Source::Path(_)) => {
+        if let Setup::Let(stmt) = store(capture, quote! { #mutability #ident }) {
+          entry.push(allow_unused(unused, stmt));
+        }
+      }
+      (Mode::Each, Source::Path(_)) => {
+        let target = target(capture);
+        entry.push(allow_unused(
+          unused,
+          quote_spanned! {at.span=>
+            let #mutability #ident #ty = #target.clone();
+          },
+        ))
+      }
+      (Mode::Ref, _) => {
+        let target = target(capture);
+        entry.push(allow_unused(
+          unused,
+          quote_spanned! {at.span=>
+            let #ident #ty = &#mutability #target;
+          },
+        ));
+      }
+      (Mode::Guard(guard, fallback), Source::Path(_)) => entry.push(allow_unused(
+        unused,
+        expand_guard(capture, *guard, fallback, target(capture)),
+      )),
+      (Mode::Try(_), _) => {
+        return Err(syn::Error::new_spanned(
+          capture,
+          with_help(
+            "`@try` captures are not allowed in `ref` closures",
+            "a `ref` closure clones its captures on each call, remove `ref` to clone once",
+          ),
+        ))
+      }
+      (_, Source::Expr(..)) => {
+        return Err(syn::Error::new_spanned(
+          capture,
+          with_help(
+            "`@name = expr` captures are not allowed in `ref` closures",
+            "bind the expression to a variable first, or remove `ref` to move it into the closure",
+          ),
+        ))
+      }
+      (Mode::Weak(..), _) => {
+        return Err(syn::Error::new_spanned(
+          capture,
+          with_help(
+            "`@weak` captures are not allowed in `ref` closures",
+            "a `ref` closure borrows the original value, remove `ref` to store a weak reference",
+          ),
+        ))
+      }
+    }
+  }
+
+  let body = with_entry(&entry, *body);
+  let closure = finish_closure(
+    bounds,
+    &wrapper,
+    &inputs,
+    &output,
+    quote! {
+      #asyncness |#(#inputs),*| #output #body
+    },
+  )?;
+  if setup.is_empty() {
+    return Ok(closure);
+  }
+  Ok(with_setup(setup, closure))
+}
+
+fn expand_async_block(
+  bounds: &Bounds,
+  captures: &[Capture],
+  block: AsyncBlock,
+) -> syn::Result<TokenStream> {
+  let AsyncBlock { async_token, block } = block;
+
+  // an async block only runs once, so there is nothing to clone again
+  if let Some(capture) = captures.iter().find(|c| matches!(c.mode, Mode::Each)) {
+    return Err(syn::Error::new_spanned(
+      capture,
+      with_help(
+        "`@each` captures are only allowed in closures",
+        "an async block only runs once, use `@var` instead",
+      ),
+    ));
+  }
+
+  let (setup, entry) = expand_captures(captures, &block.to_token_stream());
+  let block = with_entry_block(&entry, block);
+
+  let block = assert_bounds(
+    bounds,
+    None,
+    quote! {
+      #async_token move #block
+    },
+  )?;
+  Ok(with_setup(setup, block))
+}
+
+type Bounds = Punctuated<TypeParamBound, Token![+]>;
+
+/// Put the closure in a `Box`, `Rc` or `Arc` of `dyn Fn`, or assert the bounds of it.
+///
+/// The signature of `dyn Fn` comes from the annotated parameter and return types, or `_`.
+/// Bounds like `FnMut + Send` are used in the `dyn` type instead of being asserted.
+fn finish_closure(
+  bounds: &Bounds,
+  wrapper: &Option<Wrapper>,
+  inputs: &[Pat],
+  output: &ReturnType,
+  closure: TokenStream,
+) -> syn::Result<TokenStream> {
+  let wrapper = match wrapper {
+    Some(wrapper) => wrapper,
+    None => return assert_bounds(bounds, Some(inputs.len()), closure),
+  };
+
+  let types = inputs.iter().map(|pat| match pat {
+    Pat::Type(pat) => pat.ty.to_token_stream(),
+    _ => quote! { _ },
+  });
+  let ret = match output {
+    ReturnType::Type(_, ty) => ty.to_token_stream(),
+    ReturnType::Default => quote! { _ },
+  };
+  let signature = quote! { (#(#types),*) -> #ret };
+
+  let mut has_fn = false;
+  let mut has_lifetime = false;
+  let mut dyn_bounds = Vec::new();
+  for bound in bounds {
+    if let Some(fn_trait) = bare_fn_trait(bound) {
+      has_fn = true;
+      dyn_bounds.push(quote! { #fn_trait #signature });
+      continue;
+    }
+    match bound {
+      TypeParamBound::Lifetime(_) => has_lifetime = true,
+      TypeParamBound::Trait(bound) => {
+        has_fn |= bound.path.segments.last().is_some_and(|segment| {
+          segment.ident == "Fn" || segment.ident == "FnMut" || segment.ident == "FnOnce"
+        })
+      }
+      _ => {}
+    }
+    dyn_bounds.push(bound.to_token_stream());
+  }
+  if !has_fn {
+    dyn_bounds.insert(0, quote! { ::core::ops::Fn #signature });
+  }
+  // without this, the default lifetime of `Box<dyn Fn()>` is `'static`
+  if !has_lifetime {
+    dyn_bounds.push(quote! { '_ });
+  }
+
+  let (new, pointer) = match wrapper {
+    Wrapper::Box(token) => (
+      quote_spanned! {token.span=> ::std::boxed::Box::new },
+      quote! { ::std::boxed::Box },
+    ),
+    Wrapper::Rc(ident) => (
+      quote_spanned! {ident.span()=> ::std::rc::Rc::new },
+      quote! { ::std::rc::Rc },
+    ),
+    Wrapper::Arc(ident) => (
+      quote_spanned! {ident.span()=> ::std::sync::Arc::new },
+      quote! { ::std::sync::Arc },
+    ),
+  };
+  Ok(quote! {
+    #new(#closure) as #pointer<dyn #(#dyn_bounds)+*>
+  })
+}
+
+/// `Fn`, `FnMut` or `FnOnce` without the signature.
+fn bare_fn_trait(bound: &TypeParamBound) -> Option<&Ident> {
+  match bound {
+    TypeParamBound::Trait(TraitBound {
+      paren_token: None,
+      modifier: TraitBoundModifier::None,
+      lifetimes: None,
+      path,
+    }) => path
+      .get_ident()
+      .filter(|ident| *ident == "Fn" || *ident == "FnMut" || *ident == "FnOnce"),
+    _ => None,
+  }
+}
+
+/// Assert `bounds` on `result`, a bare `Fn`, `FnMut` or `FnOnce` gets the arity of the closure.
+///
+/// ```ignore
+/// {
+///   let result = move |x| ..;
+///   {
+///     fn assert_bounds<F: Fn(A0) -> R + Send, A0, R>(_: &F) {}
+///     assert_bounds(&result);
+///   }
+///   result
+/// }
+/// ```
+///
+/// The closure is not passed to `assert_bounds` directly,
+/// so its signature is not inferred from the bounds.
+fn assert_bounds(
+  bounds: &Bounds,
+  arity: Option<usize>,
+  result: TokenStream,
+) -> syn::Result<TokenStream> {
+  if bounds.is_empty() {
+    return Ok(result);
+  }
+
+  let args = (0..arity.unwrap_or(0))
+    .map(|i| Ident::new(&format!("A{}", i), Span::mixed_site()))
+    .collect::<Vec<_>>();
+  let ret = Ident::new("R", Span::mixed_site());
+  let mut has_fn = false;
+  let mut asserted = Vec::new();
+  for bound in bounds {
+    match (bare_fn_trait(bound), arity) {
+      (Some(_), None) => {
+        return Err(syn::Error::new_spanned(
+          bound,
+          with_help(
+            "`Fn` bounds are only allowed for closures",
+            "an async block is a future, use bounds like `Send + 'static`",
+          ),
+        ))
+      }
+      (Some(fn_trait), Some(_)) => {
+        has_fn = true;
+        asserted.push(quote! { #fn_trait(#(#args),*) -> #ret });
+      }
+      (None, _) => asserted.push(bound.to_token_stream()),
+    }
+  }
+
+  let generics = if has_fn {
+    quote! { , #(#args,)* #ret }
+  } else {
+    TokenStream::new()
+  };
+  let assert = Ident::new("assert_bounds", Span::mixed_site());
+  let value = Ident::new("result", Span::mixed_site());
+  // unsatisfied bounds should point to the bounds
+  let call = quote_spanned! {bounds.span()=> #assert(&#value); };
+  // items are not hygienic, so the helper is declared in a block without the user's code
+  Ok(quote! {{
+    let #value = #result;
+    {
+      fn #assert<F: #(#asserted)+* #generics>(_: &F) {}
+      #call
+    }
+    #value
+  }})
+}
+
+/// A statement executed when the closure is created.
+enum Setup {
+  /// `let pat = value;`
+  Let(TokenStream),
+  /// `@try var`, continue only if `value` is `Ok(pat)`.
+  Try {
+    pat: TokenStream,
+    value: TokenStream,
+  },
+}
+
+/// Expand the captures of `body`, and warn about the unused ones.
+fn expand_captures<'a>(
+  captures: impl IntoIterator<Item = &'a Capture>,
+  body: &TokenStream,
+) -> (Vec<Setup>, Vec<TokenStream>) {
+  let mut setup = Vec::new();
+  let mut entry = Vec::new();
+  for capture in captures {
+    let (capture_setup, capture_entry) = expand_capture(capture);
+    if is_used(capture, body) {
+      setup.push(capture_setup);
+      entry.push(capture_entry);
+    } else {
+      setup.push(Setup::Let(warn_unused(capture)));
+      setup.push(match capture_setup {
+        Setup::Let(stmt) => Setup::Let(allow_unused(true, stmt)),
+        Setup::Try { value, .. } => Setup::Try {
+          pat: quote! { _ },
+          value,
+        },
+      });
+      entry.push(allow_unused(true, capture_entry));
+    }
+  }
+  (setup, entry)
+}
+
+/// Whether `body` mentions the captured name, including inline format arguments like `"{a}"`.
+///
+/// Names starting with `_` are always considered used.
+fn is_used(capture: &Capture, body: &TokenStream) -> bool {
+  fn mentions(tokens: TokenStream, name: &str) -> bool {
+    tokens.into_iter().any(|token| match token {
+      TokenTree::Ident(ident) => ident == name,
+      TokenTree::Group(group) => mentions(group.stream(), name),
+      TokenTree::Literal(lit) => {
+        let lit = lit.to_string();
+        lit.contains(&format!("{{{}}}", name)) || lit.contains(&format!("{{{}:", name))
+      }
+      TokenTree::Punct(_) => false,
+    })
+  }
+
+  let name = capture.ident.to_string();
+  name.starts_with('_') || mentions(body.clone(), &name)
+}
+
+fn warn_unused(capture: &Capture) -> TokenStream {
+  warning(
+    capture.ident.span(),
+    &format!(
+      "`{}` is captured but never used in the closure, remove the capture",
+      capture.ident
+    ),
+  )
+}
+
+/// Our own warning is clearer than `unused_variables`, so silence it.
+fn allow_unused(unused: bool, stmt: TokenStream) -> TokenStream {
+  if unused && !stmt.is_empty() {
+    quote! {
+      #[allow(unused_variables, unused_mut)]
+      #stmt
+    }
+  } else {
+    stmt
+  }
+}
+
+/// Put the setup statements in front of the result.
+///
+/// If there are `@try` captures, the result is wrapped in `Ok`,
+/// and errors are returned as `Err`.
+fn with_setup(setup: Vec<Setup>, result: TokenStream) -> TokenStream {
+  let result = if setup.iter().any(|s| matches!(s, Setup::Try { .. })) {
+    quote! { ::core::result::Result::Ok(#result) }
+  } else {
+    result
+  };
+
+  let err = Ident::new("err", Span::mixed_site());
+  let tokens = setup
+    .into_iter()
+    .rev()
+    .fold(result, |rest, setup| match setup {
+      Setup::Let(stmt) => quote! {
+        #stmt
+        #rest
+      },
+      Setup::Try { pat, value } => quote! {
+        match #value {
+          ::core::result::Result::Ok(#pat) => { #rest }
+          ::core::result::Result::Err(#err) => ::core::result::Result::Err(#err),
+        }
+      },
+    });
+
+  quote! {{ #tokens }}
+}
+
+/// Prepend the entry-time statements to the body.
+fn with_entry(entry: &[TokenStream], body: Expr) -> TokenStream {
+  match body {
+    // avoid nested braces, which will be reported by the `unused_braces` lint
+    Expr::Block(ExprBlock {
+      attrs,
+      label: None,
+      block,
+    }) if attrs.is_empty() => with_entry_block(entry, block),
+    body if entry.iter().all(TokenStream::is_empty) => body.into_token_stream(),
+    body => quote! {{
+      #(#entry)*
+      #body
+    }},
+  }
+}
+
+fn with_entry_block(entry: &[TokenStream], block: Block) -> TokenStream {
+  if entry.iter().all(TokenStream::is_empty) {
+    block.into_token_stream()
+  } else {
+    let stmts = block.stmts;
+    quote! {{
+      #(#entry)*
+      #(#stmts)*
+    }}
+  }
+}
+
+/// Return the statements executed when the closure is created,
+/// and the statements executed when the closure is called.
+///
+/// `@var` => `let var = var.clone();`
+///
+/// `@self.db` => `let db = self.db.clone();`
+///
+/// `@name = expr` => `let name = expr;`
+///
+/// `@weak var` => `let var = downgrade(&var);`, and `let var = upgrade(&var)?;` on entry.
+///
+/// `@each var` => `let var = var.clone();`, and `let var = var.clone();` on entry.
+///
+/// `@ref var` => `let var = &var;`
+///
+/// `@try var` => `match var.try_clone() { Ok(var) => .., Err(err) => Err(err) }`
+///
+/// `@try? var` => `let var = var.try_clone()?;`
+///
+/// `@[Mode] var` => `let var = Capture::<Mode>::capture(&var);`
+///
+/// `@with(f) var` => `let var = f(&var);`
+///
+/// `@owned var` => `let var = var.to_owned();`
+///
+/// `@into var: Type` => `let var: Type = Into::into(var.clone());`
+///
+/// `@var: Type` => `let var: Type = var.clone();`, the type is put on the binding which
+/// the body sees, so it is on the entry statement of `@each`, `@weak` and the guards.
+fn expand_capture(capture: &Capture) -> (Setup, TokenStream) {
+  let Capture {
+    at,
+    mode,
+    mutability,
+    ident,
+    ..
+  } = capture;
+  let ty = typed(capture);
+
+  match mode {
+    Mode::Clone | Mode::Try(_) | Mode::Custom(_) | Mode::With(_) | Mode::Owned | Mode::Into(_) => (
+      store(capture, quote! { #mutability #ident }),
+      TokenStream::new(),
+    ),
+    Mode::Each => (
+      store(capture, ident.to_token_stream()),
+      quote_spanned! {at.span=>
+        let #mutability #ident #ty = #ident.clone();
+      },
+    ),
+    Mode::Ref => {
+      let target = target(capture);
+      (
+        Setup::Let(quote_spanned! {at.span=>
+          let #ident #ty = &#mutability #target;
+        }),
+        TokenStream::new(),
+      )
+    }
+    Mode::Weak(fallback) => {
+      let target = target(capture);
+      let msg = format!("failed to upgrade `{}`, the value has been dropped", ident);
+      let fallback = expand_fallback(fallback, &msg);
+      // a method call, so `&Rc<T>` and `&Arc<T>` are dereferenced
+      (
+        Setup::Let(quote_spanned! {at.span=>
+          let #ident = {
+            use ::clonesure::__private::Downgrade as _;
+            #target.__clonesure_downgrade()
+          };
+        }),
+        quote_spanned! {at.span=>
+          let #mutability #ident #ty = match ::clonesure::__private::Upgrade::upgrade(&#ident) {
+            ::core::option::Option::Some(#ident) => #ident,
+            ::core::option::Option::None => #fallback,
+          };
+        },
+      )
+    }
+    Mode::Guard(guard, fallback) => (
+      store(capture, ident.to_token_stream()),
+      expand_guard(capture, *guard, fallback, ident.to_token_stream()),
+    ),
+  }
+}
+
+/// Lock or borrow `handle` on entry, and bind the guard to the captured name.
+fn expand_guard(
+  capture: &Capture,
+  guard: Guard,
+  fallback: &Fallback,
+  handle: TokenStream,
+) -> TokenStream {
+  let Capture {
+    at,
+    mutability,
+    ident,
+    ..
+  } = capture;
+  let ty = typed(capture);
+
+  let (method, mutable, msg) = match guard {
+    Guard::Lock => (
+      quote! { lock },
+      true,
+      format!("failed to lock `{}`, the mutex is poisoned", ident),
+    ),
+    Guard::Read => (
+      quote! { read },
+      false,
+      format!("failed to read `{}`, the rwlock is poisoned", ident),
+    ),
+    Guard::Write => (
+      quote! { write },
+      true,
+      format!("failed to write `{}`, the rwlock is poisoned", ident),
+    ),
+    Guard::Borrow => (
+      quote! { try_borrow },
+      false,
+      format!(
+        "failed to borrow `{}`, it is already mutably borrowed",
+        ident
+      ),
+    ),
+    Guard::BorrowMut => (
+      quote! { try_borrow_mut },
+      true,
+      format!(
+        "failed to borrow `{}` mutably, it is already borrowed",
+        ident
+      ),
+    ),
+  };
+  let mutability = if mutable {
+    quote! { mut }
+  } else {
+    mutability.to_token_stream()
+  };
+
+  let guard = Ident::new("guard", Span::mixed_site());
+  let err = Ident::new("err", Span::mixed_site());
+  let on_err = match fallback {
+    // a poisoned lock still holds the guard
+    Fallback::Recover => quote! {
+      ::core::result::Result::Err(#err) => #err.into_inner()
+    },
+    fallback => {
+      let fallback = expand_fallback(fallback, &msg);
+      quote! { ::core::result::Result::Err(_) => #fallback }
+    }
+  };
+
+  quote_spanned! {at.span=>
+    let #mutability #ident #ty = match #handle.#method() {
+      ::core::result::Result::Ok(#guard) => #guard,
+      #on_err,
+    };
+  }
+}
+
+/// Store the clone of `@var`, or the value of `@name = expr`, in `pat`.
+fn store(capture: &Capture, pat: TokenStream) -> Setup {
+  let at = &capture.at;
+  // `@each` and the guards annotate the binding on entry instead
+  let ty = match capture.mode {
+    Mode::Clone | Mode::Try(_) | Mode::Custom(_) | Mode::With(_) | Mode::Owned | Mode::Into(_) => {
+      typed(capture)
+    }
+    _ => TokenStream::new(),
+  };
+  match (&capture.mode, &capture.source) {
+    (Mode::Try(question), source) => {
+      let value = match source {
+        Source::Path(_) => {
+          let target = target(capture);
+          quote_spanned! {at.span=> #target.try_clone() }
+        }
+        // the value of `@try name = expr` is already a `Result`
+        Source::Expr(_, expr) => expr.to_token_stream(),
+      };
+      match question {
+        Some(question) => Setup::Let(quote_spanned! {at.span=>
+          let #pat #ty = #value #question;
+        }),
+        None => match &capture.ty {
+          // a match arm can't annotate its binding, so coerce the value in a closure
+          Some((_, ty)) => {
+            let value_ident = Ident::new("value", Span::mixed_site());
+            Setup::Try {
+              pat,
+              value: quote_spanned! {at.span=>
+                ::core::result::Result::map(#value, |#value_ident| -> #ty { #value_ident })
+              },
+            }
+          }
+          None => Setup::Try { pat, value },
+        },
+      }
+    }
+    (Mode::Custom(mode), _) => {
+      let target = target(capture);
+      // errors like "`Capture<Mode>` is not implemented" should point to the mode
+      let capture = quote_spanned! {mode.span()=> ::clonesure::Capture::<#mode>::capture };
+      Setup::Let(quote_spanned! {at.span=>
+        let #pat #ty = #capture(&#target);
+      })
+    }
+    // a method call, so both `&str` and `String` give a `String`
+    (Mode::Owned, _) => {
+      let target = target(capture);
+      Setup::Let(quote_spanned! {at.span=>
+        let #pat #ty = #target.to_owned();
+      })
+    }
+    (Mode::Into(into), source) => {
+      let value = match source {
+        // `.clone()` of a reference like `&Path` is reported by the `noop_method_call` lint
+        Source::Path(_) => {
+          let target = target(capture);
+          quote_spanned! {at.span=> ::core::clone::Clone::clone(&#target) }
+        }
+        Source::Expr(_, expr) => expr.to_token_stream(),
+      };
+      let into = quote_spanned! {into.span()=> ::core::convert::Into::into };
+      Setup::Let(quote_spanned! {at.span=>
+        let #pat #ty = #into(#value);
+      })
+    }
+    (Mode::With(f), _) => {
+      let target = target(capture);
+      Setup::Let(quote_spanned! {at.span=>
+        let #pat #ty = #f(&#target);
+      })
+    }
+    _ => {
+      let value = cloned_value(capture);
+      // `mut` of an inferred capture is copied from the declaration, it may be unneeded
+      let allow = if capture.inferred {
+        quote! { #[allow(unused_mut)] }
+      } else {
+        TokenStream::new()
+      };
+      Setup::Let(quote_spanned! {at.span=>
+        #allow
+        let #pat #ty = #value;
+      })
+    }
+  }
+}
+
+/// `: Type` of `@var: Type`, or nothing.
+fn typed(capture: &Capture) -> TokenStream {
+  match &capture.ty {
+    Some((colon, ty)) => quote! { #colon #ty },
+    None => TokenStream::new(),
+  }
+}
+
+/// The variable or field of `@var`, the value behind `@&var`, or the value of `@name = expr`.
+fn target(capture: &Capture) -> TokenStream {
+  match (&capture.source, &capture.deref) {
+    (Source::Path(path), None) => path.to_token_stream(),
+    (Source::Path(path), Some(_)) => quote! { (*#path) },
+    (Source::Expr(_, expr), _) => quote! { (#expr) },
+  }
+}
+
+/// The clone of `@var`, or the value of `@name = expr`.
+fn cloned_value(capture: &Capture) -> TokenStream {
+  let at = &capture.at;
+  match &capture.source {
+    // errors like "`Clone` is not implemented" should point to the capture
+    Source::Path(_) => {
+      let target = target(capture);
+      quote_spanned! {at.span=> #target.clone() }
+    }
+    Source::Expr(_, expr) => expr.to_token_stream(),
+  }
+}
+
+/// `msg` is the panic message of `(or_panic)`.
+fn expand_fallback(fallback: &Fallback, msg: &str) -> TokenStream {
+  match fallback {
+    Fallback::Default => quote! { return ::core::default::Default::default() },
+    Fallback::Return(expr) => quote! { return #expr },
+    Fallback::Panic => quote! { ::core::panic!(#msg) },
+    // rejected by the parser for everything but locks, which handle it themselves
+    Fallback::Recover => unreachable!(),
+  }
+}
+
+/// Emit `msg` as a warning at `span`,
+/// a stable procedural macro can only do this by using a deprecated item.
+pub fn warning(span: Span, msg: &str) -> TokenStream {
+  quote_spanned! {span=>
+    {
+      #[deprecated(note = #msg)]
+      #[allow(non_upper_case_globals)]
+      const clonesure: () = ();
+      let () = clonesure;
+    }
+  }
+}
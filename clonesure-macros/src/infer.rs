@@ -0,0 +1,252 @@
+use crate::parse::{Capture, Mode, Source};
+use proc_macro2::{TokenStream, TokenTree};
+use syn::{
+  visit::{self, Visit},
+  Expr, ExprPath, Ident, ImplItemFn, Item, ItemFn, Macro, PatIdent, Token,
+};
+
+/// A variable which should be cloned into a `move` closure or an `async move` block.
+pub struct Inferred {
+  /// The first use in the closure, so errors point to the closure.
+  pub ident: Ident,
+  /// Whether the variable is declared with `mut`.
+  pub mutable: bool,
+}
+
+impl Inferred {
+  /// `@var` or `@mut var`.
+  pub fn into_capture(self) -> Capture {
+    let span = self.ident.span();
+    Capture {
+      at: Token![@](span),
+      mode: Mode::Clone,
+      mutability: if self.mutable {
+        Some(Token![mut](span))
+      } else {
+        None
+      },
+      deref: None,
+      ty: None,
+      source: Source::Path(std::iter::once(self.ident.clone()).collect()),
+      ident: self.ident,
+      inferred: true,
+    }
+  }
+}
+
+/// Closures and async blocks which `#[clonesure(infer)]` looks into:
+/// `move` closures and `async move` blocks which are not marked with `#[cc]`.
+pub fn is_candidate(expr: &Expr) -> bool {
+  let (attrs, by_move) = match expr {
+    Expr::Closure(closure) => (&closure.attrs, closure.capture.is_some()),
+    Expr::Async(block) => (&block.attrs, block.capture.is_some()),
+    _ => return false,
+  };
+  by_move && !attrs.iter().any(|attr| attr.path().is_ident("cc"))
+}
+
+/// Infer the clones of each candidate, in the order the candidates are visited.
+///
+/// This is a syntactic guess, a variable is cloned if it is declared outside the closure,
+/// and it is used again after the closure, or the closure is in a loop which the variable is not.
+pub fn infer(item: &Item) -> Vec<Vec<Inferred>> {
+  let mut collector = Collector::default();
+  collector.visit_item(item);
+  let Collector {
+    decls,
+    uses,
+    closures,
+    ..
+  } = collector;
+
+  // the last declaration of the name before the use, in the same function
+  let resolve = |name: &Ident, pos: usize, fn_start: usize| {
+    decls
+      .iter()
+      .rev()
+      .find(|d| d.pos < pos && d.pos >= fn_start && d.ident == *name)
+  };
+
+  closures
+    .iter()
+    .map(|closure| {
+      let mut inferred: Vec<(usize, Inferred)> = Vec::new();
+      for u in &uses {
+        if u.pos <= closure.start || u.pos >= closure.end {
+          continue;
+        }
+        let decl = match resolve(&u.ident, u.pos, u.fn_start) {
+          Some(decl) if decl.pos < closure.start => decl,
+          _ => continue,
+        };
+        if inferred.iter().any(|(pos, _)| *pos == decl.pos) {
+          continue;
+        }
+
+        let used_later = uses.iter().any(|later| {
+          later.pos > closure.end
+            && resolve(&later.ident, later.pos, later.fn_start).map(|d| d.pos) == Some(decl.pos)
+        });
+        let in_loop = closure.loops.iter().any(|start| *start > decl.pos);
+        if used_later || in_loop {
+          inferred.push((
+            decl.pos,
+            Inferred {
+              ident: u.ident.clone(),
+              mutable: decl.mutable,
+            },
+          ));
+        }
+      }
+      inferred.into_iter().map(|(_, inferred)| inferred).collect()
+    })
+    .collect()
+}
+
+struct Decl {
+  ident: Ident,
+  mutable: bool,
+  pos: usize,
+}
+
+struct Use {
+  ident: Ident,
+  pos: usize,
+  fn_start: usize,
+}
+
+struct Candidate {
+  start: usize,
+  end: usize,
+  /// Starts of the loops around the closure.
+  loops: Vec<usize>,
+}
+
+/// Record declarations, uses, candidates and loops in the order they appear.
+#[derive(Default)]
+struct Collector {
+  pos: usize,
+  fn_start: usize,
+  loops: Vec<usize>,
+  decls: Vec<Decl>,
+  uses: Vec<Use>,
+  closures: Vec<Candidate>,
+}
+
+impl Collector {
+  fn next(&mut self) -> usize {
+    self.pos += 1;
+    self.pos
+  }
+
+  fn push_use(&mut self, ident: &Ident) {
+    let pos = self.next();
+    self.uses.push(Use {
+      ident: ident.clone(),
+      pos,
+      fn_start: self.fn_start,
+    });
+  }
+
+  /// Identifiers in macro arguments may be variables, e.g. `println!("{}", a)`,
+  /// so are inline format arguments like `println!("{a}")`.
+  fn push_token_uses(&mut self, tokens: TokenStream) {
+    for token in tokens {
+      match token {
+        TokenTree::Ident(ident) => self.push_use(&ident),
+        TokenTree::Group(group) => self.push_token_uses(group.stream()),
+        TokenTree::Literal(lit) => {
+          for name in format_args(&lit.to_string()) {
+            self.push_use(&Ident::new(name, lit.span()));
+          }
+        }
+        TokenTree::Punct(_) => {}
+      }
+    }
+  }
+
+  fn in_fn(&mut self, f: impl FnOnce(&mut Self)) {
+    let fn_start = std::mem::replace(&mut self.fn_start, self.pos + 1);
+    self.next();
+    f(self);
+    self.fn_start = fn_start;
+  }
+}
+
+/// Names of the inline format arguments in a literal, e.g. `a` and `b` in `"{a} {b:?}"`.
+fn format_args(lit: &str) -> Vec<&str> {
+  let mut names = Vec::new();
+  let mut rest = lit;
+  while let Some(start) = rest.find('{') {
+    rest = &rest[start + 1..];
+    // `{{` is an escaped brace
+    if let Some(after) = rest.strip_prefix('{') {
+      rest = after;
+      continue;
+    }
+    let end = rest
+      .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+      .unwrap_or(rest.len());
+    let name = &rest[..end];
+    let is_ident = name.starts_with(|c: char| c.is_alphabetic() || c == '_') && name != "_";
+    if is_ident && rest[end..].starts_with(['}', ':']) {
+      names.push(name);
+    }
+    rest = &rest[end..];
+  }
+  names
+}
+
+impl<'ast> Visit<'ast> for Collector {
+  fn visit_expr(&mut self, expr: &'ast Expr) {
+    if is_candidate(expr) {
+      let index = self.closures.len();
+      let start = self.next();
+      self.closures.push(Candidate {
+        start,
+        end: 0,
+        loops: self.loops.clone(),
+      });
+      visit::visit_expr(self, expr);
+      self.closures[index].end = self.next();
+    } else if let Expr::ForLoop(_) | Expr::While(_) | Expr::Loop(_) = expr {
+      let start = self.next();
+      self.loops.push(start);
+      visit::visit_expr(self, expr);
+      self.loops.pop();
+    } else {
+      visit::visit_expr(self, expr);
+    }
+  }
+
+  fn visit_expr_path(&mut self, expr: &'ast ExprPath) {
+    if expr.qself.is_none() {
+      if let Some(ident) = expr.path.get_ident() {
+        self.push_use(ident);
+      }
+    }
+    visit::visit_expr_path(self, expr);
+  }
+
+  fn visit_macro(&mut self, mac: &'ast Macro) {
+    self.push_token_uses(mac.tokens.clone());
+  }
+
+  fn visit_pat_ident(&mut self, pat: &'ast PatIdent) {
+    let pos = self.next();
+    self.decls.push(Decl {
+      ident: pat.ident.clone(),
+      mutable: pat.mutability.is_some(),
+      pos,
+    });
+    visit::visit_pat_ident(self, pat);
+  }
+
+  fn visit_item_fn(&mut self, item: &'ast ItemFn) {
+    self.in_fn(|this| visit::visit_item_fn(this, item));
+  }
+
+  fn visit_impl_item_fn(&mut self, item: &'ast ImplItemFn) {
+    self.in_fn(|this| visit::visit_impl_item_fn(this, item));
+  }
+}
@@ -0,0 +1,25 @@
+//! Procedural macros for [clonesure](https://docs.rs/clonesure).
+//!
+//! Use the re-exports in `clonesure` instead of depending on this crate directly.
+
+mod attr;
+mod expand;
+mod infer;
+mod parse;
+
+use proc_macro::TokenStream;
+use syn::parse_macro_input;
+
+/// See [`clonesure::cc`](https://docs.rs/clonesure/latest/clonesure/macro.cc.html).
+#[proc_macro]
+pub fn cc(input: TokenStream) -> TokenStream {
+  expand::expand(parse_macro_input!(input as parse::Cc))
+    .unwrap_or_else(syn::Error::into_compile_error)
+    .into()
+}
+
+/// See [`clonesure::clonesure`](https://docs.rs/clonesure/latest/clonesure/attr.clonesure.html).
+#[proc_macro_attribute]
+pub fn clonesure(attr: TokenStream, item: TokenStream) -> TokenStream {
+  attr::expand(attr.into(), parse_macro_input!(item as syn::Item)).into()
+}
@@ -0,0 +1,868 @@
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{
+  braced, bracketed,
+  ext::IdentExt,
+  parenthesized,
+  parse::{Parse, ParseStream},
+  parse_quote, parse_quote_spanned,
+  punctuated::Punctuated,
+  spanned::Spanned,
+  token, Attribute, Block, Expr, ExprBlock, Ident, Lifetime, Pat, PatType, Path, ReturnType, Token,
+  Type, TypeParamBound,
+};
+
+const EXPECTED_TARGET: &str =
+  "expected a closure or an async block, e.g. `cc!(|@a, x| a + x)` or `cc!(@a; async { a })`";
+
+/// The whole input of `cc!`.
+pub struct Cc {
+  /// Bounds asserted on the result, e.g. `cc!(Fn + Send, |@a| ...)`.
+  pub bounds: Punctuated<TypeParamBound, Token![+]>,
+  /// Captures listed before `;`, e.g. `cc!(@a, @b; async move { ... })`,
+  /// or in brackets, e.g. `cc!([a, b], async move { ... })`.
+  pub captures: Vec<Capture>,
+  /// One or more closures which share the captures,
+  /// e.g. `cc!(@a; |x| a + x, |x| a - x)` or `cc!(@a; add = |x| a + x, sub = |x| a - x)`.
+  pub targets: Vec<NamedTarget>,
+}
+
+/// `target` or `name = target`.
+pub struct NamedTarget {
+  pub name: Option<(Ident, Token![=])>,
+  pub target: Target,
+}
+
+/// What `cc!` produces.
+pub enum Target {
+  Closure(Closure),
+  Async(AsyncBlock),
+}
+
+/// A closure whose parameter list may contain captures.
+pub struct Closure {
+  /// `box |...|`, `rc |...|` or `arc |...|`, put the closure in a pointer of `dyn Fn`.
+  pub wrapper: Option<Wrapper>,
+  pub asyncness: Option<Token![async]>,
+  /// `ref |...|`, borrow the environment instead of moving it.
+  pub by_ref: Option<Token![ref]>,
+  pub captures: Vec<Capture>,
+  pub inputs: Vec<Pat>,
+  pub output: ReturnType,
+  pub body: Box<Expr>,
+}
+
+/// The pointer which a closure is put in.
+pub enum Wrapper {
+  Box(Token![box]),
+  Rc(Ident),
+  Arc(Ident),
+}
+
+/// `async { ... }` or `async move { ... }`.
+pub struct AsyncBlock {
+  pub async_token: Token![async],
+  pub block: Block,
+}
+
+/// `@var`, `@mut var`, a field path like `@self.db`, or `@name = expr`,
+/// optionally prefixed by a mode like `@weak var`.
+#[derive(Clone)]
+pub struct Capture {
+  pub at: Token![@],
+  pub mode: Mode,
+  pub mutability: Option<Token![mut]>,
+  /// `@&var`, clone the value behind the reference `var`.
+  pub deref: Option<Token![&]>,
+  /// The name which the captured value is bound to.
+  pub ident: Ident,
+  /// `@var: Type`, the type of the binding which the closure body sees.
+  pub ty: Option<(Token![:], Type)>,
+  pub source: Source,
+  /// Inferred by `#[clonesure(infer)]` instead of written by the user.
+  pub inferred: bool,
+}
+
+/// Where the captured value comes from.
+#[derive(Clone)]
+pub enum Source {
+  /// `@var` or `@self.db`, the variable or field is cloned.
+  Path(Punctuated<Ident, Token![.]>),
+  /// `@name = expr`, the value of `expr` is moved.
+  Expr(Token![=], Expr),
+}
+
+/// How the captured value is stored in the closure.
+#[derive(Clone)]
+pub enum Mode {
+  /// Store the value itself.
+  Clone,
+  /// `@weak var`, store a weak reference and upgrade it on entry.
+  Weak(Fallback),
+  /// `@each var`, store the value and clone it again on entry.
+  Each,
+  /// `@ref var`, store a reference.
+  Ref,
+  /// `@try var`, store `var.try_clone()` and make `cc!` return a `Result`,
+  /// or propagate the error with `@try? var`.
+  Try(Option<Token![?]>),
+  /// `@[path] var`, store the output of `clonesure::Capture<path>`.
+  Custom(Path),
+  /// `@with(path) var`, store the output of `path(&var)`.
+  With(Path),
+  /// `@owned var`, store `var.to_owned()`.
+  Owned,
+  /// `@into var: Type`, store `Into::<Type>::into(var.clone())`.
+  Into(Ident),
+  /// `@lock var` and friends, store the value and lock or borrow it on entry.
+  Guard(Guard, Fallback),
+}
+
+/// Entry-time projections of `Mutex`, `RwLock` and `RefCell`.
+#[derive(Clone, Copy)]
+pub enum Guard {
+  /// `@lock var`, `var.lock()`
+  Lock,
+  /// `@read var`, `var.read()`
+  Read,
+  /// `@write var`, `var.write()`
+  Write,
+  /// `@borrow var`, `var.try_borrow()`
+  Borrow,
+  /// `@borrow_mut var`, `var.try_borrow_mut()`
+  BorrowMut,
+}
+
+/// What to do if an entry-time operation like upgrading a weak reference fails.
+#[derive(Clone)]
+pub enum Fallback {
+  /// Return `Default::default()`.
+  Default,
+  /// `(or = expr)`, return `expr`.
+  Return(Expr),
+  /// `(or_panic)`, panic.
+  Panic,
+  /// `(recover)`, ignore the poisoning of a lock.
+  Recover,
+}
+
+impl Parse for Cc {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    if input.is_empty() {
+      return Err(input.error(EXPECTED_TARGET));
+    }
+
+    // a closure or a capture never starts with an identifier,
+    // other inputs like `cc!(s1)` are reported as not a closure below
+    let mut bounds = Punctuated::new();
+    if (input.peek(Ident) && !input.peek2(Token![=]) && !peek_wrapper(input))
+      || input.peek(Lifetime)
+    {
+      let fork = input.fork();
+      if parse_bounds(&fork).is_ok() && fork.peek(Token![,]) {
+        bounds = parse_bounds(input)?;
+        input.parse::<Token![,]>()?;
+      }
+    }
+
+    let mut captures = Vec::new();
+    if input.peek(token::Bracket) {
+      // `[a, mut b]` is the `@` syntax without `@`,
+      // so the call is still formattable when all captures are valid expressions
+      let content;
+      bracketed!(content in input);
+      captures = parse_capture_list(&content)?;
+      input.parse::<Option<Token![,]>>()?;
+    } else if input.peek(Token![@]) {
+      loop {
+        captures.extend(Capture::parse_all(input)?);
+
+        let lookahead = input.lookahead1();
+        if lookahead.peek(Token![;]) {
+          input.parse::<Token![;]>()?;
+          break;
+        } else if lookahead.peek(Token![,]) {
+          input.parse::<Token![,]>()?;
+          if input.peek(Token![;]) {
+            input.parse::<Token![;]>()?;
+            break;
+          }
+        } else {
+          return Err(lookahead.error());
+        }
+      }
+    }
+
+    let mut targets: Vec<NamedTarget> = vec![input.parse()?];
+    while input.peek(Token![,]) {
+      input.parse::<Token![,]>()?;
+      if input.is_empty() {
+        break;
+      }
+      let span = input.span();
+      let target: NamedTarget = input.parse()?;
+      if target.name.is_some() != targets[0].name.is_some() {
+        return Err(syn::Error::new(
+          span,
+          with_help(
+            "expected all closures to be named, or none of them",
+            "named closures like `ok = |v| ...` are returned in a struct, others in a tuple",
+          ),
+        ));
+      }
+      targets.push(target);
+    }
+
+    Ok(Cc {
+      bounds,
+      captures,
+      targets,
+    })
+  }
+}
+
+impl Parse for NamedTarget {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    let name = if input.peek(Ident) && input.peek2(Token![=]) && !input.peek2(Token![==]) {
+      Some((input.parse()?, input.parse()?))
+    } else {
+      None
+    };
+    Ok(NamedTarget {
+      name,
+      target: input.parse()?,
+    })
+  }
+}
+
+impl Parse for Target {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    let is_async_block = input.peek(Token![async])
+      && (input.peek2(token::Brace) || (input.peek2(Token![move]) && input.peek3(token::Brace)));
+
+    if is_async_block {
+      let async_token = input.parse()?;
+      // `cc` will implicitly move its environment, so `move` is optional
+      input.parse::<Option<Token![move]>>()?;
+      Ok(Target::Async(AsyncBlock {
+        async_token,
+        block: input.parse()?,
+      }))
+    } else {
+      Ok(Target::Closure(input.parse()?))
+    }
+  }
+}
+
+/// `rc` and `arc` are only wrappers in front of a closure.
+fn peek_wrapper(input: ParseStream) -> bool {
+  let is_closure = |input: ParseStream| {
+    input.peek(Token![|])
+      || input.peek(Token![||])
+      || input.peek(Token![move])
+      || input.peek(Token![ref])
+      || input.peek(Token![async])
+  };
+  let fork = input.fork();
+  match fork.parse::<Ident>() {
+    Ok(ident) => (ident == "rc" || ident == "arc") && is_closure(&fork),
+    Err(_) => false,
+  }
+}
+
+impl Parse for Closure {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    let wrapper = if input.peek(Token![box]) {
+      Some(Wrapper::Box(input.parse()?))
+    } else if peek_wrapper(input) {
+      let ident: Ident = input.parse()?;
+      Some(if ident == "rc" {
+        Wrapper::Rc(ident)
+      } else {
+        Wrapper::Arc(ident)
+      })
+    } else {
+      None
+    };
+    let asyncness = input.parse()?;
+    // `cc` will implicitly move its environment, so `move` is optional
+    let by_ref = if input.peek(Token![ref]) {
+      Some(input.parse()?)
+    } else {
+      input.parse::<Option<Token![move]>>()?;
+      None
+    };
+
+    let mut captures: Vec<Capture> = Vec::new();
+    let mut inputs = Vec::new();
+
+    if input.peek(Token![||]) {
+      input.parse::<Token![||]>()?;
+    } else if input.peek(Token![|]) {
+      input.parse::<Token![|]>()?;
+      loop {
+        if input.peek(Token![|]) {
+          break;
+        }
+
+        // captures and parameters can be mixed in any order
+        if input.peek(Token![@]) {
+          captures.extend(Capture::parse_all(input)?);
+        } else {
+          inputs.push(parse_closure_param(input)?);
+        }
+
+        let lookahead = input.lookahead1();
+        if lookahead.peek(Token![|]) {
+          break;
+        } else if lookahead.peek(Token![,]) {
+          input.parse::<Token![,]>()?;
+        } else {
+          return Err(lookahead.error());
+        }
+      }
+      input.parse::<Token![|]>()?;
+
+      check_shadowing(&captures, &inputs)?;
+    } else {
+      return Err(input.error(EXPECTED_TARGET));
+    }
+
+    let output: ReturnType = input.parse()?;
+    let body = match output {
+      // like normal closures, a return type requires a block body
+      ReturnType::Type(..) => Expr::Block(ExprBlock {
+        attrs: Vec::new(),
+        label: None,
+        block: input.parse::<Block>()?,
+      }),
+      ReturnType::Default => input.parse()?,
+    };
+
+    Ok(Closure {
+      wrapper,
+      asyncness,
+      by_ref,
+      captures,
+      inputs,
+      output,
+      body: Box::new(body),
+    })
+  }
+}
+
+/// Captures without `@` separated by `,`, e.g. `a, mut b, weak c`.
+pub fn parse_capture_list(input: ParseStream) -> syn::Result<Vec<Capture>> {
+  let list = input.parse_terminated(
+    |input| Capture::parse_after(input, Token![@](input.span())),
+    Token![,],
+  )?;
+  Ok(list.into_iter().flatten().collect())
+}
+
+/// A parameter would shadow the capture in the body, no matter where it is.
+pub fn check_shadowing(captures: &[Capture], inputs: &[Pat]) -> syn::Result<()> {
+  let mut params = Vec::new();
+  for pat in inputs {
+    pat_idents(pat, &mut params);
+  }
+  match captures.iter().find(|c| params.contains(&&c.ident)) {
+    Some(capture) => Err(syn::Error::new_spanned(
+      capture,
+      with_help(
+        &format!(
+          "`{}` is both captured and a closure parameter",
+          capture.ident
+        ),
+        "rename the capture with `@name = expr`, or rename the parameter",
+      ),
+    )),
+    None => Ok(()),
+  }
+}
+
+/// Collect the variables bound by a closure parameter.
+fn pat_idents<'a>(pat: &'a Pat, idents: &mut Vec<&'a Ident>) {
+  match pat {
+    Pat::Ident(pat) => idents.push(&pat.ident),
+    Pat::Type(pat) => pat_idents(&pat.pat, idents),
+    Pat::Reference(pat) => pat_idents(&pat.pat, idents),
+    Pat::Paren(pat) => pat_idents(&pat.pat, idents),
+    Pat::Tuple(pat) => pat.elems.iter().for_each(|pat| pat_idents(pat, idents)),
+    Pat::TupleStruct(pat) => pat.elems.iter().for_each(|pat| pat_idents(pat, idents)),
+    Pat::Slice(pat) => pat.elems.iter().for_each(|pat| pat_idents(pat, idents)),
+    Pat::Struct(pat) => pat
+      .fields
+      .iter()
+      .for_each(|field| pat_idents(&field.pat, idents)),
+    _ => {}
+  }
+}
+
+/// `@[Mode] var` is a mode, while `@[a, b]` is a group of captures.
+fn peek_bracket_mode(input: ParseStream) -> bool {
+  let fork = input.fork();
+  fork.parse::<TokenTree>().is_ok()
+    && (fork.peek(Ident)
+      || fork.peek(Token![self])
+      || fork.peek(Token![mut])
+      || fork.peek(Token![&]))
+}
+
+impl Capture {
+  /// Parse `@var`, `@var{a, mut b}` which captures many fields of `var`,
+  /// or `@(a, b)` and `@[a, b]` which are the same as `@a, @b`.
+  pub fn parse_all(input: ParseStream) -> syn::Result<Vec<Self>> {
+    let at = input.parse()?;
+    Capture::parse_after(input, at)
+  }
+
+  /// Parse the capture after `@`, which is omitted in the bracketed list.
+  fn parse_after(input: ParseStream, at: Token![@]) -> syn::Result<Vec<Self>> {
+    if input.peek(token::Paren) || (input.peek(token::Bracket) && !peek_bracket_mode(input)) {
+      let content;
+      if input.peek(token::Paren) {
+        parenthesized!(content in input);
+      } else {
+        bracketed!(content in input);
+      }
+      return parse_capture_list(&content);
+    }
+
+    let mode = input.parse()?;
+    let mutability = input.parse()?;
+    let deref: Option<Token![&]> = input.parse()?;
+
+    let mut path = Punctuated::new();
+    if input.peek(Token![self]) {
+      path.push_value(Ident::parse_any(input)?);
+    } else if input.peek(Ident) {
+      path.push_value(input.parse()?);
+    } else {
+      return Err(input.error(with_help(
+        "expected a variable name after `@`",
+        "use `@var` to clone a variable, use `@mut var` to clone a mutable variable",
+      )));
+    }
+
+    while input.peek(Token![.]) {
+      path.push_punct(input.parse()?);
+      if !input.peek(Ident) {
+        return Err(input.error(with_help(
+          "expected a field name after `.`",
+          "the clone is bound to the last field name, so it must be a named field",
+        )));
+      }
+      path.push_value(input.parse()?);
+    }
+
+    if input.peek(token::Brace) {
+      return Capture::parse_fields(input, at, mode, mutability, deref, path);
+    }
+
+    if let (Some(deref), true) = (deref, input.peek(Token![=])) {
+      return Err(syn::Error::new(
+        deref.span,
+        with_help(
+          "`&` is only allowed in front of a variable",
+          "use `@name = expr` to move the value of `expr`",
+        ),
+      ));
+    }
+
+    // `@var as name` binds the clone to `name`
+    let rename: Option<Ident> = if input.peek(Token![as]) {
+      input.parse::<Token![as]>()?;
+      Some(input.parse()?)
+    } else {
+      None
+    };
+    let ty = if input.peek(Token![:]) {
+      Some((input.parse()?, input.parse()?))
+    } else {
+      None
+    };
+    if let (Mode::Into(into), None) = (&mode, &ty) {
+      return Err(syn::Error::new(
+        into.span(),
+        with_help(
+          "`@into` needs the type to convert to",
+          "use `@into var: Type`, e.g. `@into path: PathBuf`",
+        ),
+      ));
+    }
+
+    let is_self = path.len() == 1 && path[0] == "self";
+    let (ident, source) = if let Some(rename) = rename {
+      (rename, Source::Path(path))
+    } else if is_self {
+      // `self` can't be rebound, so the clone of it is bound to `this`
+      let this = Ident::new("this", path[0].span());
+      if input.peek(Token![=]) {
+        return Err(syn::Error::new_spanned(
+          &path,
+          with_help(
+            "cannot bind a value to `self`",
+            "use `@name = expr` with another name",
+          ),
+        ));
+      }
+      (this, Source::Path(path))
+    } else if path.len() == 1 && input.peek(Token![=]) {
+      (
+        path[0].clone(),
+        Source::Expr(input.parse()?, parse_capture_expr(input)?),
+      )
+    } else {
+      (path.last().unwrap().clone(), Source::Path(path))
+    };
+
+    Ok(vec![Capture {
+      at,
+      mode,
+      mutability,
+      deref,
+      ident,
+      ty,
+      source,
+      inferred: false,
+    }])
+  }
+
+  /// `@state{db, mut cache, weak ui}` is `@state.db, @mut state.cache, @weak state.ui`.
+  fn parse_fields(
+    input: ParseStream,
+    at: Token![@],
+    mode: Mode,
+    mutability: Option<Token![mut]>,
+    deref: Option<Token![&]>,
+    path: Punctuated<Ident, Token![.]>,
+  ) -> syn::Result<Vec<Self>> {
+    if !matches!(mode, Mode::Clone) || mutability.is_some() || deref.is_some() {
+      return Err(syn::Error::new(
+        at.span,
+        with_help(
+          "a group of fields can't have a mode",
+          "put the mode on each field, e.g. `@state{mut a, weak b}`",
+        ),
+      ));
+    }
+
+    let content;
+    braced!(content in input);
+    let fields = parse_capture_list(&content)?;
+    fields
+      .into_iter()
+      .map(|mut field| {
+        let field_path = match &field.source {
+          Source::Path(field_path) if field_path[0] != "self" => field_path,
+          _ => {
+            return Err(syn::Error::new_spanned(
+              &field,
+              with_help(
+                "expected a field name",
+                "use `@var{a, b}` to clone the fields `var.a` and `var.b`",
+              ),
+            ))
+          }
+        };
+        let mut full = path.clone();
+        for ident in field_path {
+          full.push_punct(Token![.](ident.span()));
+          full.push_value(ident.clone());
+        }
+        field.source = Source::Path(full);
+        Ok(field)
+      })
+      .collect()
+  }
+}
+
+impl Parse for Mode {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    // a mode is followed by the variable, so `@weak` alone still clones a variable named `weak`
+    let is_mode = |name: &str, has_args: bool| {
+      input.peek(Ident)
+        && input.fork().parse::<Ident>().unwrap() == name
+        && (input.peek2(Ident)
+          || input.peek2(Token![self])
+          || input.peek2(Token![mut])
+          || input.peek2(Token![&])
+          || (has_args && input.peek2(token::Paren)))
+    };
+
+    let parse_fallback = |default| -> syn::Result<Fallback> {
+      if input.peek(token::Paren) {
+        let content;
+        parenthesized!(content in input);
+        content.parse()
+      } else {
+        Ok(default)
+      }
+    };
+
+    if is_mode("weak", true) {
+      let weak: Ident = input.parse()?;
+      let fallback = parse_fallback(Fallback::Default)?;
+      if let Fallback::Recover = fallback {
+        return Err(syn::Error::new(
+          weak.span(),
+          with_help(
+            "`recover` is only allowed for locks",
+            "use `(or = expr)` to return `expr`, or `(or_panic)` to panic",
+          ),
+        ));
+      }
+      return Ok(Mode::Weak(fallback));
+    }
+
+    for (name, guard) in [
+      ("lock", Guard::Lock),
+      ("read", Guard::Read),
+      ("write", Guard::Write),
+      ("borrow", Guard::Borrow),
+      ("borrow_mut", Guard::BorrowMut),
+    ] {
+      if is_mode(name, true) {
+        let ident: Ident = input.parse()?;
+        let fallback = parse_fallback(Fallback::Panic)?;
+        if let (Fallback::Recover, Guard::Borrow | Guard::BorrowMut) = (&fallback, guard) {
+          return Err(syn::Error::new(
+            ident.span(),
+            with_help(
+              "`recover` is only allowed for locks",
+              "use `(or = expr)` to return `expr`, or `(or_panic)` to panic",
+            ),
+          ));
+        }
+        return Ok(Mode::Guard(guard, fallback));
+      }
+    }
+
+    if is_mode("with", true) {
+      let with: Ident = input.parse()?;
+      let help = "use `@with(path::to::f) var` to capture the output of `f(&var)`";
+      if !input.peek(token::Paren) {
+        return Err(syn::Error::new(
+          with.span(),
+          with_help("expected a function after `with`", help),
+        ));
+      }
+      let content;
+      parenthesized!(content in input);
+      let path = match content.parse::<Path>() {
+        Ok(path) if content.is_empty() => path,
+        _ => return Err(content.error(with_help("expected a function path", help))),
+      };
+      return Ok(Mode::With(path));
+    }
+
+    if is_mode("into", false) {
+      return Ok(Mode::Into(input.parse()?));
+    }
+
+    // `@rc var` and `@arc var` are `@with(Rc::clone) var` and `@with(Arc::clone) var`,
+    // which also accept `&Rc<T>` and `&Arc<T>` by deref coercion
+    if is_mode("rc", false) {
+      let rc: Ident = input.parse()?;
+      return Ok(Mode::With(
+        parse_quote_spanned!(rc.span()=> ::std::rc::Rc::clone),
+      ));
+    }
+    if is_mode("arc", false) {
+      let arc: Ident = input.parse()?;
+      return Ok(Mode::With(
+        parse_quote_spanned!(arc.span()=> ::std::sync::Arc::clone),
+      ));
+    }
+
+    if is_mode("owned", false) {
+      input.parse::<Ident>()?;
+      return Ok(Mode::Owned);
+    }
+
+    if input.peek(token::Bracket) {
+      let content;
+      bracketed!(content in input);
+      let path: Path = content.parse()?;
+      // built-in modes can be written in lowercase
+      let builtin = match path.get_ident() {
+        Some(ident) if ident == "clone" => Some("Clone"),
+        Some(ident) if ident == "copy" => Some("Copy"),
+        Some(ident) if ident == "to_owned" => Some("ToOwned"),
+        Some(ident) if ident == "weak" => Some("Weak"),
+        Some(ident) if ident == "rc" => Some("Rc"),
+        Some(ident) if ident == "arc" => Some("Arc"),
+        _ => None,
+      };
+      return Ok(Mode::Custom(match builtin {
+        Some(name) => {
+          let name = Ident::new(name, path.span());
+          parse_quote!(::clonesure::mode::#name)
+        }
+        None => path,
+      }));
+    }
+
+    if input.peek(Token![ref]) {
+      input.parse::<Token![ref]>()?;
+      return Ok(Mode::Ref);
+    }
+
+    if input.peek(Token![try]) {
+      input.parse::<Token![try]>()?;
+      return Ok(Mode::Try(input.parse()?));
+    }
+
+    if is_mode("each", false) {
+      input.parse::<Ident>()?;
+      return Ok(Mode::Each);
+    }
+
+    Ok(Mode::Clone)
+  }
+}
+
+impl Parse for Fallback {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    let help =
+      "use `(or = expr)` to return `expr`, `(or_panic)` to panic, or `(recover)` to ignore poisoning";
+    let key = input.parse::<Ident>().map_err(|err| {
+      syn::Error::new(
+        err.span(),
+        with_help("expected `or`, `or_panic` or `recover`", help),
+      )
+    })?;
+
+    if key == "or" {
+      input.parse::<Token![=]>()?;
+      Ok(Fallback::Return(input.parse()?))
+    } else if key == "or_panic" {
+      Ok(Fallback::Panic)
+    } else if key == "recover" {
+      Ok(Fallback::Recover)
+    } else {
+      Err(syn::Error::new(
+        key.span(),
+        with_help("expected `or`, `or_panic` or `recover`", help),
+      ))
+    }
+  }
+}
+
+impl ToTokens for Capture {
+  fn to_tokens(&self, tokens: &mut TokenStream) {
+    self.at.to_tokens(tokens);
+    self.mutability.to_tokens(tokens);
+    self.deref.to_tokens(tokens);
+    match &self.source {
+      Source::Path(path) => {
+        path.to_tokens(tokens);
+        if path.last() != Some(&self.ident) {
+          <Token![as]>::default().to_tokens(tokens);
+          self.ident.to_tokens(tokens);
+        }
+        if let Some((colon, ty)) = &self.ty {
+          colon.to_tokens(tokens);
+          ty.to_tokens(tokens);
+        }
+      }
+      Source::Expr(eq, expr) => {
+        self.ident.to_tokens(tokens);
+        if let Some((colon, ty)) = &self.ty {
+          colon.to_tokens(tokens);
+          ty.to_tokens(tokens);
+        }
+        eq.to_tokens(tokens);
+        expr.to_tokens(tokens);
+      }
+    }
+  }
+}
+
+/// `Fn + Send + 'static`
+fn parse_bounds(input: ParseStream) -> syn::Result<Punctuated<TypeParamBound, Token![+]>> {
+  let mut bounds = Punctuated::new();
+  loop {
+    bounds.push_value(input.parse()?);
+    if !input.peek(Token![+]) {
+      return Ok(bounds);
+    }
+    bounds.push_punct(input.parse()?);
+  }
+}
+
+/// Parse the expression of `@name = expr`.
+///
+/// The expression ends at the first top level `,`, `|` or `;`,
+/// so expressions containing them must be wrapped in parentheses.
+fn parse_capture_expr(input: ParseStream) -> syn::Result<Expr> {
+  let tokens = input.step(|cursor| {
+    let mut tokens = TokenStream::new();
+    let mut rest = *cursor;
+    while let Some((tt, next)) = rest.token_tree() {
+      match &tt {
+        TokenTree::Punct(punct) if matches!(punct.as_char(), ',' | '|' | ';') => break,
+        _ => {
+          tokens.extend(Some(tt));
+          rest = next;
+        }
+      }
+    }
+    Ok((tokens, rest))
+  })?;
+
+  if tokens.is_empty() {
+    return Err(input.error("expected an expression after `=`"));
+  }
+  syn::parse2(tokens).map_err(|err| {
+    syn::Error::new(
+      err.span(),
+      with_help(
+        &err.to_string(),
+        "wrap the expression in parentheses if it contains `,` or `|`",
+      ),
+    )
+  })
+}
+
+/// Same as the parameter parsing of `syn::ExprClosure`.
+fn parse_closure_param(input: ParseStream) -> syn::Result<Pat> {
+  let attrs = input.call(Attribute::parse_outer)?;
+  let mut pat = Pat::parse_single(input)?;
+
+  if input.peek(Token![:]) {
+    Ok(Pat::Type(PatType {
+      attrs,
+      pat: Box::new(pat),
+      colon_token: input.parse()?,
+      ty: input.parse()?,
+    }))
+  } else {
+    match &mut pat {
+      Pat::Const(pat) => pat.attrs = attrs,
+      Pat::Ident(pat) => pat.attrs = attrs,
+      Pat::Lit(pat) => pat.attrs = attrs,
+      Pat::Macro(pat) => pat.attrs = attrs,
+      Pat::Or(pat) => pat.attrs = attrs,
+      Pat::Paren(pat) => pat.attrs = attrs,
+      Pat::Path(pat) => pat.attrs = attrs,
+      Pat::Range(pat) => pat.attrs = attrs,
+      Pat::Reference(pat) => pat.attrs = attrs,
+      Pat::Rest(pat) => pat.attrs = attrs,
+      Pat::Slice(pat) => pat.attrs = attrs,
+      Pat::Struct(pat) => pat.attrs = attrs,
+      Pat::Tuple(pat) => pat.attrs = attrs,
+      Pat::TupleStruct(pat) => pat.attrs = attrs,
+      Pat::Type(_) => unreachable!(),
+      Pat::Wild(pat) => pat.attrs = attrs,
+      _ => {}
+    }
+    Ok(pat)
+  }
+}
+
+/// Append a help line to an error message.
+pub fn with_help(msg: &str, help: &str) -> String {
+  format!("{}\n\n= help: {}", msg, help)
+}
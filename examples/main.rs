@@ -1,4 +1,12 @@
 use clonesure::cc;
+use std::{
+  cell::RefCell,
+  fs::File,
+  future::Future,
+  rc::Rc,
+  sync::{Arc, Mutex},
+  task::{Context, Poll, Waker},
+};
 
 fn main() {
   // `cc` will implicitly move its environment
@@ -45,7 +53,6 @@ fn main() {
   );
 
   // with closure params
-  // cloned vars must be in front of closure params
   let s1 = String::from("111");
   let s2 = String::from("222");
   let s3 = String::from("333");
@@ -69,4 +76,216 @@ fn main() {
     })(s3, &s4),
     "111222333444"
   );
+
+  // captures and closure params can be listed in any order
+  let s1 = String::from("111");
+  let s2 = String::from("222");
+  assert_eq!(
+    cc!(|s3: &str, @s1, &s4: &i32, @s2| format!("{}{}{}{}", s1, s2, s3, s4))("333", &444),
+    "111222333444"
+  );
+
+  // list captures in brackets without `@`,
+  // rustfmt formats the call if every capture is a valid expression
+  let s1 = String::from("111");
+  let s2 = String::from("222");
+  assert_eq!(
+    cc!([s1, s2], |s3: &str| { format!("{}{}{}", s1, s2, s3) })("333"),
+    "111222333"
+  );
+
+  // assert bounds of the closure, a bare `Fn` gets the arity of the closure
+  let s1 = String::from("111");
+  let f = cc!(Fn + Send + 'static, |@s1, s2: &str| s1.clone() + s2);
+  assert_eq!(f("222"), "111222");
+
+  // put the closure in a `Box`, `Rc` or `Arc` of `dyn Fn`,
+  // the signature comes from the annotated types, and the bounds are added to the `dyn` type
+  let s1 = String::from("111");
+  let handler: Box<dyn Fn(&str) -> String + Send> =
+    cc!(Send, box |@s1, s2: &str| -> String { s1.clone() + s2 });
+  assert_eq!(handler("222"), "111222");
+
+  // clone fields, the clone is bound to the last field name
+  let handler = Handler {
+    db: String::from("111"),
+  };
+  assert_eq!(cc!(|@handler.db| db)(), "111");
+  assert_eq!(handler.callback()(), "111");
+  // clone many fields, each field can have its own mode
+  let f = cc!(|@handler{mut db}| {
+    db.push_str("222");
+    db
+  });
+  assert_eq!(f(), "111222");
+  // group captures with `@(a, b)` or `@[a, b]`, `@&var` clones the value behind a reference
+  let db = &handler.db;
+  assert_eq!(cc!(|@(&db, s2)| db + &s2)(), "111222");
+  assert_eq!(cc!(|@[mut s1, s2]| { s1.push_str(&s2); s1 })(), "111222");
+  // `@var: Type` annotates the clone, e.g. to coerce it to a trait object
+  let value = Rc::new(s1.clone());
+  let debug = cc!(|@value: Rc<dyn std::fmt::Debug>| format!("{:?}", value));
+  assert_eq!(debug(), "\"111\"");
+  // `@var as name` binds the clone to another name
+  assert_eq!(cc!(|@handler.db as s1| s1)(), "111");
+  // `@self` is bound to `this`, e.g. for `self: Rc<Self>` receivers
+  let handler = Rc::new(handler);
+  let len = handler.clone().len();
+  assert_eq!(len(), 3);
+  drop(handler);
+  assert_eq!(len(), 0);
+
+  // move the value of an expression into the closure
+  // the expression is evaluated once when the closure is created
+  let s1 = String::from("111");
+  let mut counter = cc!(|@s = s1.clone() + "222", @mut count = 0| {
+    count += 1;
+    format!("{}{}", s, count)
+  });
+  assert_eq!(counter(), "1112221");
+  assert_eq!(counter(), "1112222");
+
+  // store a weak reference of a `Rc` or `Arc`, and upgrade it when the closure is called
+  let s1 = Rc::new(String::from("111"));
+  let len = cc!(|@weak s1| s1.len());
+  let len_or = cc!(|@weak(or = 42) s1| s1.len());
+  assert_eq!(len(), 3);
+  // references of `Rc` and `Arc` work too, e.g. the arguments of callbacks
+  let len_of = |s1: &Rc<String>| cc!(|@weak s1| s1.len());
+  assert_eq!(len_of(&s1)(), 3);
+  drop(s1);
+  // return `Default::default()` if the value has been dropped
+  assert_eq!(len(), 0);
+  // or return the given value
+  assert_eq!(len_or(), 42);
+
+  // async blocks, list cloned variables before `;`
+  let s1 = String::from("111");
+  let s2 = String::from("222");
+  assert_eq!(block_on(cc!(@s1, @s2; async { s1 + &s2 })), "111222");
+
+  // async closures
+  let s1 = String::from("111");
+  let f = cc!(async |@s1, s2: &str| format!("{}{}", s1, s2));
+  assert_eq!(block_on(f("222")), "111222");
+  assert_eq!(block_on(f("333")), "111333");
+
+  // many closures can share the captures, each closure clones the captures which it uses
+  let s1 = String::from("111");
+  let (f1, f2) = cc!(@s1; || s1 + "222", || s1 + "333");
+  assert_eq!(f1(), "111222");
+  assert_eq!(f2(), "111333");
+  // named closures are returned in a struct
+  let callbacks = cc!(@s1; ok = |v: i32| format!("{}{}", s1, v), err = |e: &str| s1 + e);
+  assert_eq!((callbacks.ok)(222), "111222");
+  assert_eq!((callbacks.err)("333"), "111333");
+  // a shared expression is evaluated once, and cloned into each closure
+  let count = RefCell::new(0);
+  let next = || {
+    *count.borrow_mut() += 1;
+    *count.borrow()
+  };
+  let (f1, f2) = cc!(@n = next(); || n, || n + 1);
+  assert_eq!((f1(), f2(), *count.borrow()), (1, 2, 1));
+
+  // clone again every time the closure is called,
+  // so each returned future owns its own clone
+  let s1 = String::from("111");
+  let f = cc!(|@each s1, s2: String| async move { s1 + &s2 });
+  assert_eq!(block_on(f(String::from("222"))), "111222");
+  assert_eq!(block_on(f(String::from("333"))), "111333");
+
+  // `@each` also makes closures which consume the clone `Fn`
+  let s1 = String::from("111");
+  let f = cc!(|@each s1| s1);
+  assert_eq!(f(), "111");
+  assert_eq!(f(), "111");
+  assert_eq!(
+    (2..4)
+      .map(cc!(|@each mut s1, n| {
+        s1.push_str(&n.to_string());
+        s1
+      }))
+      .collect::<Vec<_>>(),
+    ["1112", "1113"]
+  );
+
+  // `ref` closures borrow variables which are not cloned,
+  // cloned variables are cloned from the borrowed variable every time the closure is called,
+  // so the closure can still be called many times, e.g. by `retain` or `sort_by`
+  let s1 = String::from("111");
+  let s2 = String::from("222");
+  let f = cc!(ref |@s1| s1 + &s2);
+  assert_eq!(f(), "111222");
+  assert_eq!(f(), "111222");
+  assert_eq!(s2, "222"); // s2 is borrowed, not moved
+  let mut v = vec![String::from("111"), String::from("222")];
+  v.retain(cc!(ref |@s1, s: &String| *s == s1 || *s == s2));
+  assert_eq!(v.len(), 2);
+
+  // `@ref` borrows a variable in a normal closure
+  let s1 = String::from("111");
+  let f = cc!(|@ref s1| s1.len());
+  assert_eq!(f(), 3);
+  assert_eq!(s1, "111");
+
+  // `@try var` calls `var.try_clone()`, and `cc` returns a `Result`
+  // use `@try? var` to propagate the error with `?`
+  let exe = File::open(std::env::current_exe().unwrap()).unwrap();
+  let len = cc!(|@try exe| exe.metadata().unwrap().len()).unwrap();
+  assert!(len() > 0);
+
+  // capture with the `Capture` trait, e.g. `@[to_owned] var` converts `&str` to `String`
+  let s1 = "111";
+  assert_eq!(cc!(|@[to_owned] s1| s1 + "222")(), "111222");
+  // `@rc var` and `@arc var` call `Rc::clone(&var)` and `Arc::clone(&var)`,
+  // `@with(f) var` calls `f(&var)`
+  let shared = Arc::new(String::from("111"));
+  let f = cc!(|@arc shared, @with(str::len) s1| shared.len() + s1);
+  assert_eq!(f(), 6);
+  // `@owned var` calls `ToOwned::to_owned`, `@into var: Type` calls `Into::into`,
+  // so closures of borrowed values like `&str` or `&Path` can be `'static`
+  let path = std::path::Path::new("111");
+  let f: Box<dyn Fn() -> String> =
+    Box::new(cc!(|@owned s1, @into path: std::path::PathBuf| s1.clone() + path.to_str().unwrap()));
+  assert_eq!(f(), "111111");
+
+  // clone the handle, and lock it every time the closure is called
+  let count = Arc::new(Mutex::new(0));
+  let inc = cc!(|@lock count, n: i32| *count += n);
+  inc(1);
+  inc(2);
+  assert_eq!(*count.lock().unwrap(), 3);
+  // `@borrow` and `@borrow_mut` borrow a `RefCell`
+  let s1 = Rc::new(RefCell::new(String::from("111")));
+  let push = cc!(|@borrow_mut s1| s1.push_str("222"));
+  push();
+  assert_eq!(cc!(|@borrow s1| s1.clone())(), "111222");
+}
+
+struct Handler {
+  db: String,
+}
+
+impl Handler {
+  fn callback(&self) -> impl FnOnce() -> String {
+    // same as `let db = self.db.clone();`
+    cc!(|@self.db| db)
+  }
+
+  fn len(self: Rc<Self>) -> impl Fn() -> usize {
+    // store a weak reference of `self`, and upgrade it to `this`
+    cc!(|@weak self| this.db.len())
+  }
+}
+
+/// A minimal executor for the futures above.
+fn block_on<F: Future>(future: F) -> F::Output {
+  let mut future = Box::pin(future);
+  let mut cx = Context::from_waker(Waker::noop());
+  loop {
+    if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+      return output;
+    }
+  }
 }
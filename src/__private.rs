@@ -0,0 +1,52 @@
+//! Runtime helpers used by the expanded code of the macros, not public API.
+
+use std::{rc, sync};
+
+/// Smart pointers which can be downgraded to weak references, used by `@weak`.
+///
+/// The method is called with the method syntax, so `&Rc<T>` and `&Arc<T>` work too,
+/// and it has an unusual name, so it does not shadow methods of the pointee.
+pub trait Downgrade {
+  type Weak;
+
+  fn __clonesure_downgrade(&self) -> Self::Weak;
+}
+
+impl<T: ?Sized> Downgrade for rc::Rc<T> {
+  type Weak = rc::Weak<T>;
+
+  fn __clonesure_downgrade(&self) -> Self::Weak {
+    rc::Rc::downgrade(self)
+  }
+}
+
+impl<T: ?Sized> Downgrade for sync::Arc<T> {
+  type Weak = sync::Weak<T>;
+
+  fn __clonesure_downgrade(&self) -> Self::Weak {
+    sync::Arc::downgrade(self)
+  }
+}
+
+/// Weak references which can be upgraded to smart pointers, used by `@weak`.
+pub trait Upgrade {
+  type Strong;
+
+  fn upgrade(&self) -> Option<Self::Strong>;
+}
+
+impl<T: ?Sized> Upgrade for rc::Weak<T> {
+  type Strong = rc::Rc<T>;
+
+  fn upgrade(&self) -> Option<Self::Strong> {
+    rc::Weak::upgrade(self)
+  }
+}
+
+impl<T: ?Sized> Upgrade for sync::Weak<T> {
+  type Strong = sync::Arc<T>;
+
+  fn upgrade(&self) -> Option<Self::Strong> {
+    sync::Weak::upgrade(self)
+  }
+}
@@ -0,0 +1,117 @@
+use std::{rc, sync};
+
+/// A way to capture a value into a closure, used by `@[mode] var`.
+///
+/// `@[Mode] var` will be translated to:
+///
+/// ```ignore
+/// let var = clonesure::Capture::<Mode>::capture(&var);
+/// ```
+///
+/// The built-in modes in [`mode`] can be written in lowercase, e.g. `@[weak] var`.
+///
+/// # Examples
+///
+/// ```
+/// use clonesure::{cc, Capture};
+///
+/// /// Capture the length instead of the whole string.
+/// struct Len;
+///
+/// impl Capture<Len> for String {
+///   type Output = usize;
+///
+///   fn capture(&self) -> usize {
+///     self.len()
+///   }
+/// }
+///
+/// let s1 = String::from("111");
+/// assert_eq!(cc!(|@[Len] s1| s1 + 1)(), 4);
+/// ```
+pub trait Capture<Mode> {
+  type Output;
+
+  fn capture(&self) -> Self::Output;
+}
+
+/// Built-in modes of [`Capture`].
+pub mod mode {
+  /// `@[clone] var`, same as `@var`.
+  pub struct Clone;
+
+  /// `@[copy] var`, copy the value.
+  pub struct Copy;
+
+  /// `@[to_owned] var`, convert a reference `&T` to `T::Owned`, e.g. `&str` to `String`.
+  pub struct ToOwned;
+
+  /// `@[weak] var`, store a weak reference of a `Rc` or `Arc`.
+  ///
+  /// Unlike `@weak var`, the weak reference will not be upgraded when the closure is called.
+  pub struct Weak;
+
+  /// `@[rc] var`, clone a `Rc` with `Rc::clone`, like `@rc var`,
+  /// which satisfies `clippy::clone_on_ref_ptr`.
+  pub struct Rc;
+
+  /// `@[arc] var`, clone an `Arc` with `Arc::clone`, like `@arc var`,
+  /// which satisfies `clippy::clone_on_ref_ptr`.
+  pub struct Arc;
+}
+
+impl<T: Clone> Capture<mode::Clone> for T {
+  type Output = T;
+
+  fn capture(&self) -> T {
+    self.clone()
+  }
+}
+
+impl<T: Copy> Capture<mode::Copy> for T {
+  type Output = T;
+
+  fn capture(&self) -> T {
+    *self
+  }
+}
+
+impl<T: ?Sized + ToOwned> Capture<mode::ToOwned> for &T {
+  type Output = T::Owned;
+
+  fn capture(&self) -> T::Owned {
+    (*self).to_owned()
+  }
+}
+
+impl<T: ?Sized> Capture<mode::Weak> for rc::Rc<T> {
+  type Output = rc::Weak<T>;
+
+  fn capture(&self) -> rc::Weak<T> {
+    rc::Rc::downgrade(self)
+  }
+}
+
+impl<T: ?Sized> Capture<mode::Weak> for sync::Arc<T> {
+  type Output = sync::Weak<T>;
+
+  fn capture(&self) -> sync::Weak<T> {
+    sync::Arc::downgrade(self)
+  }
+}
+
+impl<T: ?Sized> Capture<mode::Rc> for rc::Rc<T> {
+  type Output = rc::Rc<T>;
+
+  fn capture(&self) -> rc::Rc<T> {
+    rc::Rc::clone(self)
+  }
+}
+
+impl<T: ?Sized> Capture<mode::Arc> for sync::Arc<T> {
+  type Output = sync::Arc<T>;
+
+  fn capture(&self) -> sync::Arc<T> {
+    sync::Arc::clone(self)
+  }
+}
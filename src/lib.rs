@@ -4,7 +4,49 @@
 ///
 /// When define parameters of a closure, use `@var` to clone a variable, use `@mut var` to clone a mutable variable.
 ///
-/// **Cloned variables must be in front of closure parameters.**
+/// Fields can be cloned too, the clone is bound to the last field name, e.g. `@self.db` will be bound to `db`.
+///
+/// Use `@self` to clone `self` in methods, the clone is bound to `this`, which also works for `self: Rc<Self>` and `self: Arc<Self>` receivers, e.g. `@weak self` stores a weak reference of `self`. Use `@var as name` to bind the clone to another name, e.g. `@self as widget` or `@self.db as conn`.
+///
+/// Use `@var{a, b}` to clone many fields of `var`, each field can have its own mode, e.g. `@state{db, mut cache, weak ui}` is the same as `@state.db, @mut state.cache, @weak state.ui`.
+///
+/// Use `@(a, b)` or `@[a, b]` to clone many variables at once, each can have its own mode. `@&var` clones the value behind the reference `var`, so `@&config` binds an owned `Config` when `config: &Config`.
+///
+/// Use `@var: Type` to annotate the type of the captured value, e.g. `@svc: Arc<dyn Service>` coerces an `Arc<MyService>` to a trait object, so different closures can capture the same type. With modes like `@weak` and `@lock`, the type is the one the body sees, e.g. `@weak svc: Rc<dyn Service>`.
+///
+/// Use `@name = expr` to move the value of `expr` into the closure as `name`, the expression is evaluated once when the closure is created. Wrap the expression in parentheses if it contains `,` or `|`.
+///
+/// Use `@weak var` to store a weak reference of a `Rc` or `Arc`, and upgrade it when the closure is called. If the value has been dropped, the closure returns `Default::default()`, use `@weak(or = expr) var` to return `expr` instead, or `@weak(or_panic) var` to panic.
+///
+/// Cloned variables can also be listed before `;`, which is required for async blocks, e.g. `cc!(@a, @b; async { a + b })`. Async closures are supported too, e.g. `cc!(async |@a, x| a + x)`.
+///
+/// List more closures after the captures to share the captures, e.g. `cc!(@db; |v| db.save(v), |k| db.load(k))` returns a tuple of closures, and `cc!(@db; save = |v| db.save(v), load = |k| db.load(k))` returns a struct whose fields are the closures. Each closure clones the captures which it uses, an expression like `@n = expr` is still evaluated once and cloned into each closure, and with `@try` captures `cc` returns a `Result` of the whole tuple or struct.
+///
+/// Use `@each var` to clone the variable again every time the closure is called, e.g. `cc!(|@each client, req| async move { client.send(req).await })` is `Fn` and each returned future owns its own `client`. This also keeps closures which consume the variable `Fn`, e.g. `cc!(|@each s| s)`.
+///
+/// Use `ref |...|` to borrow variables which are not cloned instead of moving them, e.g. `v.sort_by(cc!(ref |a, b| keys[*a].cmp(&keys[*b])))`. In a `ref` closure, captures are cloned from the borrowed variables every time the closure is called, so the closure stays `Fn` or `FnMut`, and `@name = expr` or `@try` captures are not allowed. Use `@ref var` to borrow a variable in a normal closure.
+///
+/// Use `@try var` to clone types like `File` or `TcpStream` with `var.try_clone()`, then `cc` returns a `Result` of the closure. Use `@try? var` to propagate the error with `?` instead. `@try name = expr` takes the value from `expr`, which is a `Result`.
+///
+/// Use `@[Mode] var` to capture the variable with the [`Capture<Mode>`](Capture) trait, which can be implemented for your own modes. Built-in modes are `@[clone]`, `@[copy]`, `@[to_owned]`, `@[weak]`, `@[rc]` and `@[arc]`.
+///
+/// Use `@rc var` or `@arc var` to clone with `Rc::clone(&var)` or `Arc::clone(&var)`, which satisfies `clippy::clone_on_ref_ptr`. Use `@with(path::to::f) var` to capture the output of `f(&var)`, e.g. `@with(Arc::clone) var`.
+///
+/// Use `@owned var` to capture `var.to_owned()`, or `@into var: Type` to capture `Into::<Type>::into` of the clone, e.g. `@owned name` turns `name: &str` into a `String`, and `@into path: PathBuf` turns `path: &Path` into a `PathBuf`, so the closure does not borrow its inputs.
+///
+/// Use `@lock var` to clone a `Arc<Mutex<T>>` and lock it every time the closure is called, so the body sees the guard as `var`. `@read var` and `@write var` do the same for `RwLock`, `@borrow var` and `@borrow_mut var` for `RefCell`. If the lock is poisoned or the `RefCell` is already borrowed, the closure panics, use `@lock(or = expr) var` to return `expr` instead, or `@lock(recover) var` to ignore the poisoning. In a `ref` closure, the borrowed variable is locked without cloning.
+///
+/// Captures which are never mentioned in the closure are reported as warnings, name the capture with a leading `_` like `@_guard = lock()` to keep it on purpose. `@mut` captures which are never mutated are reported by the `unused_mut` lint.
+///
+/// Captures and closure parameters can be listed in any order, e.g. `cc!(|x: u32, @db, &y| ...)`, the captures are removed from the parameter list.
+///
+/// Captures can also be listed in brackets without `@`, e.g. `cc!([a, mut b, weak c], move |x| a + x)`. rustfmt can't format the `@` syntax, but it formats the whole call like a normal closure if every capture in the brackets is a valid expression, like `a`, `self.db` or `name = expr`.
+///
+/// Put bounds in front to assert them on the result, e.g. `cc!(Fn + Send + 'static, |@a, x| a + x)` fails to compile at the `cc` call if the closure is only `FnOnce` or not `Send`. A bare `Fn`, `FnMut` or `FnOnce` gets the arity of the closure, and bounds like `Send + 'static` can be asserted on async blocks too. The closure does not get its parameter types from the call site with bounds, annotate them if needed.
+///
+/// Use `box |...|`, `rc |...|` or `arc |...|` to put the closure in a `Box`, `Rc` or `Arc` of `dyn Fn`, e.g. `cc!(box |@a, e: Event| a.handle(e))` is a `Box<dyn Fn(Event) -> _>`. The signature comes from the annotated parameter and return types, and the bounds in front are used in the `dyn` type, e.g. `cc!(FnMut + Send, box |x: i32| ...)` is a `Box<dyn FnMut(i32) -> _ + Send>`.
+///
+/// Use the [`#[clonesure]`](clonesure) attribute on a function or an `impl` block to mark closures and async blocks with `#[cc(...)]` instead of wrapping them in `cc`, e.g. `#[cc(a, mut b)] move |x| a + b + x` is the same as `cc!([a, mut b], move |x| a + b + x)`, and the closure stays formattable by rustfmt. Use `#[clonesure(infer)]` to clone variables which are moved into a `move` closure but used again after it automatically, mark the closure with `#[cc]` to opt out, and use `#[clonesure(infer, report)]` to report the inferred captures as warnings.
 ///
 /// E.g.:
 ///
@@ -26,6 +68,14 @@
 ///
 /// ```
 /// use clonesure::cc;
+/// use std::{
+///   cell::RefCell,
+///   fs::File,
+///   future::Future,
+///   rc::Rc,
+///   sync::{Arc, Mutex},
+///   task::{Context, Poll, Waker},
+/// };
 ///
 /// fn main() {
 ///   // `cc` will implicitly move its environment
@@ -72,7 +122,6 @@
 ///   );
 ///
 ///   // with closure params
-///   // cloned vars must be in front of closure params
 ///   let s1 = String::from("111");
 ///   let s2 = String::from("222");
 ///   let s3 = String::from("333");
@@ -96,50 +145,266 @@
 ///     })(s3, &s4),
 ///     "111222333444"
 ///   );
+///
+///   // captures and closure params can be listed in any order
+///   let s1 = String::from("111");
+///   let s2 = String::from("222");
+///   assert_eq!(
+///     cc!(|s3: &str, @s1, &s4: &i32, @s2| format!("{}{}{}{}", s1, s2, s3, s4))("333", &444),
+///     "111222333444"
+///   );
+///
+///   // list captures in brackets without `@`,
+///   // rustfmt formats the call if every capture is a valid expression
+///   let s1 = String::from("111");
+///   let s2 = String::from("222");
+///   assert_eq!(
+///     cc!([s1, s2], |s3: &str| { format!("{}{}{}", s1, s2, s3) })("333"),
+///     "111222333"
+///   );
+///
+///   // assert bounds of the closure, a bare `Fn` gets the arity of the closure
+///   let s1 = String::from("111");
+///   let f = cc!(Fn + Send + 'static, |@s1, s2: &str| s1.clone() + s2);
+///   assert_eq!(f("222"), "111222");
+///
+///   // put the closure in a `Box`, `Rc` or `Arc` of `dyn Fn`,
+///   // the signature comes from the annotated types, and the bounds are added to the `dyn` type
+///   let s1 = String::from("111");
+///   let handler: Box<dyn Fn(&str) -> String + Send> =
+///     cc!(Send, box |@s1, s2: &str| -> String { s1.clone() + s2 });
+///   assert_eq!(handler("222"), "111222");
+///
+///   // clone fields, the clone is bound to the last field name
+///   let handler = Handler {
+///     db: String::from("111"),
+///   };
+///   assert_eq!(cc!(|@handler.db| db)(), "111");
+///   assert_eq!(handler.callback()(), "111");
+///   // clone many fields, each field can have its own mode
+///   let f = cc!(|@handler{mut db}| {
+///     db.push_str("222");
+///     db
+///   });
+///   assert_eq!(f(), "111222");
+///   // group captures with `@(a, b)` or `@[a, b]`, `@&var` clones the value behind a reference
+///   let db = &handler.db;
+///   assert_eq!(cc!(|@(&db, s2)| db + &s2)(), "111222");
+///   assert_eq!(cc!(|@[mut s1, s2]| { s1.push_str(&s2); s1 })(), "111222");
+///   // `@var: Type` annotates the clone, e.g. to coerce it to a trait object
+///   let value = Rc::new(s1.clone());
+///   let debug = cc!(|@value: Rc<dyn std::fmt::Debug>| format!("{:?}", value));
+///   assert_eq!(debug(), "\"111\"");
+///   // `@var as name` binds the clone to another name
+///   assert_eq!(cc!(|@handler.db as s1| s1)(), "111");
+///   // `@self` is bound to `this`, e.g. for `self: Rc<Self>` receivers
+///   let handler = Rc::new(handler);
+///   let len = handler.clone().len();
+///   assert_eq!(len(), 3);
+///   drop(handler);
+///   assert_eq!(len(), 0);
+///
+///   // move the value of an expression into the closure
+///   // the expression is evaluated once when the closure is created
+///   let s1 = String::from("111");
+///   let mut counter = cc!(|@s = s1.clone() + "222", @mut count = 0| {
+///     count += 1;
+///     format!("{}{}", s, count)
+///   });
+///   assert_eq!(counter(), "1112221");
+///   assert_eq!(counter(), "1112222");
+///
+///   // store a weak reference of a `Rc` or `Arc`, and upgrade it when the closure is called
+///   let s1 = Rc::new(String::from("111"));
+///   let len = cc!(|@weak s1| s1.len());
+///   let len_or = cc!(|@weak(or = 42) s1| s1.len());
+///   assert_eq!(len(), 3);
+///   // references of `Rc` and `Arc` work too, e.g. the arguments of callbacks
+///   let len_of = |s1: &Rc<String>| cc!(|@weak s1| s1.len());
+///   assert_eq!(len_of(&s1)(), 3);
+///   drop(s1);
+///   // return `Default::default()` if the value has been dropped
+///   assert_eq!(len(), 0);
+///   // or return the given value
+///   assert_eq!(len_or(), 42);
+///
+///   // async blocks, list cloned variables before `;`
+///   let s1 = String::from("111");
+///   let s2 = String::from("222");
+///   assert_eq!(block_on(cc!(@s1, @s2; async { s1 + &s2 })), "111222");
+///
+///   // async closures
+///   let s1 = String::from("111");
+///   let f = cc!(async |@s1, s2: &str| format!("{}{}", s1, s2));
+///   assert_eq!(block_on(f("222")), "111222");
+///   assert_eq!(block_on(f("333")), "111333");
+///
+///   // many closures can share the captures, each closure clones the captures which it uses
+///   let s1 = String::from("111");
+///   let (f1, f2) = cc!(@s1; || s1 + "222", || s1 + "333");
+///   assert_eq!(f1(), "111222");
+///   assert_eq!(f2(), "111333");
+///   // named closures are returned in a struct
+///   let callbacks = cc!(@s1; ok = |v: i32| format!("{}{}", s1, v), err = |e: &str| s1 + e);
+///   assert_eq!((callbacks.ok)(222), "111222");
+///   assert_eq!((callbacks.err)("333"), "111333");
+///   // a shared expression is evaluated once, and cloned into each closure
+///   let count = RefCell::new(0);
+///   let next = || {
+///     *count.borrow_mut() += 1;
+///     *count.borrow()
+///   };
+///   let (f1, f2) = cc!(@n = next(); || n, || n + 1);
+///   assert_eq!((f1(), f2(), *count.borrow()), (1, 2, 1));
+///
+///   // clone again every time the closure is called,
+///   // so each returned future owns its own clone
+///   let s1 = String::from("111");
+///   let f = cc!(|@each s1, s2: String| async move { s1 + &s2 });
+///   assert_eq!(block_on(f(String::from("222"))), "111222");
+///   assert_eq!(block_on(f(String::from("333"))), "111333");
+///
+///   // `@each` also makes closures which consume the clone `Fn`
+///   let s1 = String::from("111");
+///   let f = cc!(|@each s1| s1);
+///   assert_eq!(f(), "111");
+///   assert_eq!(f(), "111");
+///   assert_eq!(
+///     (2..4)
+///       .map(cc!(|@each mut s1, n| {
+///         s1.push_str(&n.to_string());
+///         s1
+///       }))
+///       .collect::<Vec<_>>(),
+///     ["1112", "1113"]
+///   );
+///
+///   // `ref` closures borrow variables which are not cloned,
+///   // cloned variables are cloned from the borrowed variable every time the closure is called,
+///   // so the closure can still be called many times, e.g. by `retain` or `sort_by`
+///   let s1 = String::from("111");
+///   let s2 = String::from("222");
+///   let f = cc!(ref |@s1| s1 + &s2);
+///   assert_eq!(f(), "111222");
+///   assert_eq!(f(), "111222");
+///   assert_eq!(s2, "222"); // s2 is borrowed, not moved
+///   let mut v = vec![String::from("111"), String::from("222")];
+///   v.retain(cc!(ref |@s1, s: &String| *s == s1 || *s == s2));
+///   assert_eq!(v.len(), 2);
+///
+///   // `@ref` borrows a variable in a normal closure
+///   let s1 = String::from("111");
+///   let f = cc!(|@ref s1| s1.len());
+///   assert_eq!(f(), 3);
+///   assert_eq!(s1, "111");
+///
+///   // `@try var` calls `var.try_clone()`, and `cc` returns a `Result`
+///   // use `@try? var` to propagate the error with `?`
+///   let exe = File::open(std::env::current_exe().unwrap()).unwrap();
+///   let len = cc!(|@try exe| exe.metadata().unwrap().len()).unwrap();
+///   assert!(len() > 0);
+///
+///   // capture with the `Capture` trait, e.g. `@[to_owned] var` converts `&str` to `String`
+///   let s1 = "111";
+///   assert_eq!(cc!(|@[to_owned] s1| s1 + "222")(), "111222");
+///   // `@rc var` and `@arc var` call `Rc::clone(&var)` and `Arc::clone(&var)`,
+///   // `@with(f) var` calls `f(&var)`
+///   let shared = Arc::new(String::from("111"));
+///   let f = cc!(|@arc shared, @with(str::len) s1| shared.len() + s1);
+///   assert_eq!(f(), 6);
+///   // `@owned var` calls `ToOwned::to_owned`, `@into var: Type` calls `Into::into`,
+///   // so closures of borrowed values like `&str` or `&Path` can be `'static`
+///   let path = std::path::Path::new("111");
+///   let f: Box<dyn Fn() -> String> =
+///     Box::new(cc!(|@owned s1, @into path: std::path::PathBuf| s1.clone() + path.to_str().unwrap()));
+///   assert_eq!(f(), "111111");
+///
+///   // clone the handle, and lock it every time the closure is called
+///   let count = Arc::new(Mutex::new(0));
+///   let inc = cc!(|@lock count, n: i32| *count += n);
+///   inc(1);
+///   inc(2);
+///   assert_eq!(*count.lock().unwrap(), 3);
+///   // `@borrow` and `@borrow_mut` borrow a `RefCell`
+///   let s1 = Rc::new(RefCell::new(String::from("111")));
+///   let push = cc!(|@borrow_mut s1| s1.push_str("222"));
+///   push();
+///   assert_eq!(cc!(|@borrow s1| s1.clone())(), "111222");
+/// }
+///
+/// struct Handler {
+///   db: String,
+/// }
+///
+/// impl Handler {
+///   fn callback(&self) -> impl FnOnce() -> String {
+///     // same as `let db = self.db.clone();`
+///     cc!(|@self.db| db)
+///   }
+///
+///   fn len(self: Rc<Self>) -> impl Fn() -> usize {
+///     // store a weak reference of `self`, and upgrade it to `this`
+///     cc!(|@weak self| this.db.len())
+///   }
+/// }
+///
+/// /// A minimal executor for the futures above.
+/// fn block_on<F: Future>(future: F) -> F::Output {
+///   let mut future = Box::pin(future);
+///   let mut cx = Context::from_waker(Waker::noop());
+///   loop {
+///     if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+///       return output;
+///     }
+///   }
 /// }
 /// ```
-#[macro_export]
-macro_rules! cc {
-  // simple closures without params
-  // cc will implicitly move its environment
-  (|| $($t:tt)*) => {
-    move || $($t)*
-  };
-  (move || $($t:tt)*) => {
-    move || $($t)*
-  };
+pub use clonesure_macros::cc;
 
-  // public interface, eat the first `|`
-  (|$($t:tt)*) => {
-    cc!(@@impl mut[] clone[] $($t)*)
-  };
-  // public interface, eat the leading `move |`
-  (move |$($t:tt)*) => {
-    cc!(@@impl mut[] clone[] $($t)*)
-  };
+/// Expand closures and async blocks marked with `#[cc(...)]` in a function or an `impl` block.
+///
+/// `#[cc(a, mut b)] move |x| ...` is the same as `cc!([a, mut b], move |x| ...)`, but the closure is written with normal Rust syntax, so rustfmt and rust-analyzer treat it as a normal closure. Nested closures and async blocks are expanded too, closures inside other macros like `vec![...]` are not.
+///
+/// Use `#[clonesure(infer)]` to clone variables automatically: if a variable is moved into a `move` closure or an `async move` block, but it is used again after the closure, or the closure is in a loop which the variable is not, it is cloned as if it is listed in `#[cc(...)]`. This is a syntactic guess, mark the closure with `#[cc]` to opt out. Use `#[clonesure(infer, report)]` to report the inferred captures as warnings.
+///
+/// ```
+/// use clonesure::clonesure;
+/// use std::rc::Rc;
+///
+/// #[clonesure]
+/// fn main() {
+///   let s1 = String::from("111");
+///   let s2 = Rc::new(String::from("222"));
+///   let f = #[cc(s1, weak s2)]
+///   move |s3: &str| {
+///     let f = #[cc(s1)]
+///     || s1 + &s2 + s3;
+///     f()
+///   };
+///   assert_eq!(f("333"), "111222333");
+///   assert_eq!(s1, "111");
+/// }
+///
+/// #[clonesure(infer)]
+/// fn infer() {
+///   let s1 = String::from("111");
+///   // s1 is used again below, so it is cloned into the closure
+///   let f = move || s1.len();
+///   assert_eq!(f(), 3);
+///   // the closure is in a loop, so s1 is cloned in each iteration
+///   for _ in 0..2 {
+///     let f = move || s1.len();
+///     assert_eq!(f(), 3);
+///   }
+///   assert_eq!(s1, "111");
+/// }
+/// # infer();
+/// ```
+pub use clonesure_macros::clonesure;
 
-  // eat `@mut xx`, store in the array `mut`
-  (@@impl mut[$($mut:ident)*] clone[$($clone:ident)*] @mut $var:ident $($t:tt)*)=>{
-    cc!(@@impl mut[$($mut)* $var] clone[$($clone)*] $($t)*)
-  };
-  // eat `@xx`, store in the array `clone`
-  (@@impl mut[$($mut:ident)*] clone[$($clone:ident)*] @$var:ident $($t:tt)*)=>{
-    cc!(@@impl mut[$($mut)*] clone[$($clone)* $var] $($t)*)
-  };
-  // eat `,`
-  (@@impl mut[$($mut:ident)*] clone[$($clone:ident)*] , $($t:tt)*)=>{
-    cc!(@@impl mut[$($mut)*] clone[$($clone)*] $($t)*)
-  };
-  // otherwise, generate result
-  (@@impl mut[$($mut:ident)*] clone[$($clone:ident)*] $($t:tt)*)=>{{
-    $(
-      let mut $mut = $mut.clone();
-    )*
+mod capture;
 
-    $(
-      let $clone = $clone.clone();
-    )*
+pub use capture::{mode, Capture};
 
-    move |$($t)*
-  }};
-}
+#[doc(hidden)]
+pub mod __private;
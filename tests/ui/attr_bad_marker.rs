@@ -0,0 +1,9 @@
+use clonesure::clonesure;
+
+#[clonesure]
+fn main() {
+  let s1 = String::from("111");
+  let f = #[cc = s1]
+  move || s1.len();
+  f();
+}
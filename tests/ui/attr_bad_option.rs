@@ -0,0 +1,4 @@
+use clonesure::clonesure;
+
+#[clonesure(infer, verbose)]
+fn main() {}
@@ -0,0 +1,7 @@
+use clonesure::cc;
+use std::{cell::RefCell, rc::Rc};
+
+fn main() {
+  let s = Rc::new(RefCell::new(String::new()));
+  cc!(|@borrow(recover) s| s.len());
+}
@@ -0,0 +1,5 @@
+use clonesure::cc;
+
+fn main() {
+  cc!(|@s =, x: u32| s + x);
+}
@@ -0,0 +1,6 @@
+use clonesure::cc;
+
+fn main() {
+  let s1 = String::from("111");
+  cc!(|@s1, @"222"| s1);
+}
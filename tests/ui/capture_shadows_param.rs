@@ -0,0 +1,7 @@
+use clonesure::cc;
+
+fn main() {
+  let s1 = String::from("111");
+  let s2 = String::from("222");
+  cc!(|@s1, s2: &str, @mut s2| s1 + s2);
+}
@@ -0,0 +1,6 @@
+use clonesure::cc;
+
+fn main() {
+  let pair = (String::from("111"), String::from("222"));
+  cc!(|@pair.0| pair);
+}
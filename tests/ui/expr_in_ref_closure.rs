@@ -0,0 +1,8 @@
+use clonesure::cc;
+
+fn main() {
+  let s1 = String::from("111");
+  let keys = vec![1, 2];
+  let mut v = vec![0, 1];
+  v.sort_by(cc!(ref |@n = s1.len(), a: &usize, b: &usize| keys[*a].cmp(&keys[*b]).then(n.cmp(&n))));
+}
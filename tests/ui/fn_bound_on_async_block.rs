@@ -0,0 +1,6 @@
+use clonesure::cc;
+
+fn main() {
+  let s1 = String::from("111");
+  let _ = cc!(Fn + Send, @s1; async { s1 });
+}
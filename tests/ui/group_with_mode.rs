@@ -0,0 +1,13 @@
+use clonesure::cc;
+use std::rc::Rc;
+
+struct State {
+  db: Rc<String>,
+}
+
+fn main() {
+  let state = State {
+    db: Rc::new(String::new()),
+  };
+  cc!(|@weak state{db}| db.len());
+}
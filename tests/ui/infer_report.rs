@@ -0,0 +1,49 @@
+#![deny(warnings)]
+
+use clonesure::clonesure;
+
+#[clonesure(infer, report)]
+fn main() {
+  // used again after the closure, `mut` is copied but not reported as unused
+  let mut v = vec![1];
+  v.push(2);
+  let f = move || v.len();
+  v.push(3);
+  f();
+
+  // used again in an inline format argument
+  let s0 = String::from("000");
+  let e = move || s0.len();
+  e();
+  let _ = format!("{s0}");
+
+  // in a loop which the variable is not
+  let s1 = String::from("111");
+  for _ in 0..2 {
+    let g = move || s1.len();
+    g();
+  }
+
+  // shadowed after the closure, the later use is another variable
+  let s2 = String::from("222");
+  let h = move || s2.len();
+  let s2 = 2;
+  h();
+  let _ = s2;
+
+  // shadowed in the closure, the outer variable is not captured
+  let s3 = String::from("333");
+  let i = move || {
+    let s3 = 3;
+    s3
+  };
+  i();
+  let _ = &s3;
+
+  // opted out with `#[cc]`
+  let n = 1;
+  let j = #[cc]
+  move || n;
+  j();
+  let _ = n;
+}
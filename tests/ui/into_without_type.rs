@@ -0,0 +1,6 @@
+use clonesure::cc;
+
+fn main() {
+  let s1 = "111";
+  cc!(|@into s1| s1);
+}
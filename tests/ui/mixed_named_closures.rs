@@ -0,0 +1,6 @@
+use clonesure::cc;
+
+fn main() {
+  let s1 = String::from("111");
+  cc!(@s1; ok = || s1.len(), || s1.is_empty());
+}
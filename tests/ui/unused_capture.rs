@@ -0,0 +1,20 @@
+#![deny(warnings)]
+
+use clonesure::cc;
+
+struct Handle;
+
+impl Handle {
+  fn try_clone(&self) -> Result<Handle, ()> {
+    Ok(Handle)
+  }
+}
+
+fn main() {
+  let s1 = String::from("111");
+  let s2 = String::from("222");
+  cc!(|@mut s1, @s2| s1.len())();
+  let handle = Handle;
+  let _ = cc!(|@s1, @try handle| s1.len());
+  let _: Result<(_, _), ()> = cc!(@try handle; || 1, || 2);
+}
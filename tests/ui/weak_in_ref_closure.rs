@@ -0,0 +1,7 @@
+use clonesure::cc;
+use std::rc::Rc;
+
+fn main() {
+  let s1 = Rc::new(String::from("111"));
+  cc!(ref |@weak s1| s1.len());
+}